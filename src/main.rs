@@ -1,329 +1,6914 @@
 use anyhow::{Context, Result};
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use secrecy::{zeroize::Zeroizing, ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use std::{
-    path::Path,
+    collections::HashMap,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex},
 };
-use tokio::{fs, sync::mpsc};
 
 const CHAT_FILE: &str = "chat.md";
-const API_URL: &str = "https://api.deepseek.com/v1/chat/completions";
+const DEFAULT_API_URL: &str = "https://api.deepseek.com/v1/chat/completions";
+const DEFAULT_MODEL: &str = "deepseek-chat";
+const DEFAULT_REASONING_FIELD: &str = "reasoning_effort";
+const DEFAULT_EMPTY_RETRIES: u32 = 2;
+const DEFAULT_FALLBACK_AFTER: Duration = Duration::from_secs(20);
+const TITLE_MAX_CHARS: usize = 80;
+const TITLE_PROMPT: &str = "Write a title of at most six words for a conversation that starts with the \
+message below. Reply with the title only, without quotes.";
+const COMPLETE_PROMPT: &str = "Continue the user's unfinished text from exactly where it stops, in \
+the same voice. Reply with the continuation only; begin it with a space if it starts a new word.";
+const SUMMARY_PROMPT: &str = "Summarize the conversation excerpt below in a few sentences, keeping \
+decisions, facts and open questions. Reply with the summary only.";
+const CLARIFY_PROMPT: &str = "If the user's request is ambiguous or missing details you need, ask \
+brief clarifying questions instead of guessing; otherwise answer normally.";
+const DEFAULT_CLARIFY_MAX_CHARS: usize = 80;
 const MAX_CONTEXT_MESSAGES: usize = 6;
 const MESSAGE_SEPARATOR: &str = "\n***\n";
 const DOUBLE_NEWLINE: &str = "\n\n";
+const NOTE_INFO: &str = "chat-md";
+const REPLY_END: &str = "<!-- end of reply -->";
+const PREVIEW_CHARS: usize = 80;
+const GIST_API_URL: &str = "https://api.github.com/gists";
+const BENCH_PREVIEW_CHARS: usize = 200;
+const WORD_DIFF_TIMEOUT: Duration = Duration::from_secs(2);
+const PAUSE_FILE: &str = ".chat-pause";
+const MEMORY_FILE: &str = "memory.md";
+const IGNORE_FILE: &str = ".chatignore";
+const SNIPPET_DIR: &str = "snippets";
+const BACKUP_DIR: &str = ".chat-backups";
+const MAX_CONNECTION_RETRIES: u32 = 2;
+const CONNECTION_RETRY_DELAY: Duration = Duration::from_millis(200);
+const TOOL_TIMEOUT: Duration = Duration::from_secs(10);
+const TOOL_OUTPUT_LIMIT: usize = 16 * 1024;
+const DEFAULT_BULK_FILES: usize = 8;
+const DEFAULT_BULK_WINDOW: Duration = Duration::from_millis(200);
+const DEFAULT_CONTEXT_TOKENS: usize = 64_000;
+const DEFAULT_INCLUDE_SHARE: f64 = 0.25;
+const CHARS_PER_TOKEN: usize = 4;
+const DIFF_LIMIT: usize = 32 * 1024;
+const APPEND_CHECK_BYTES: usize = 4 * 1024;
+const STATUS_HEAD_BYTES: u64 = 16 * 1024;
+const MAX_TOOL_ROUNDS: usize = 8;
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+const KEY_COOLDOWN: Duration = Duration::from_secs(60);
+const DEFAULT_RATE_LIMIT_FLOOR: u64 = 1;
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+const DEFAULT_ERROR_WINDOW: Duration = Duration::from_secs(600);
+const KEY_RELOAD_AFTER: usize = 3;
+const STREAM_TIMEOUT: Duration = Duration::from_secs(600);
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const STREAM_WRITE_INTERVAL: Duration = Duration::from_millis(250);
+const DEFAULT_SERVICE_NAME: &str = "chat-md";
+const KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const MISSING_DIR_INTERVAL: Duration = Duration::from_secs(2);
+const MISSING_DIR_CHECKS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    System,
+    /// Higher-priority instructions for models with an instruction hierarchy (o1-style).
+    Developer,
+    User,
+    Assistant,
+    /// Output of a tool call, answering the assistant message that requested it.
+    Tool,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Role::System => "system",
+            Role::Developer => "developer",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        };
+        f.write_str(name)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Message {
-    role: String,
+    role: Role,
+    /// Null when the assistant only requests tool calls; some providers send an array of
+    /// content blocks instead of a string.
+    #[serde(default, deserialize_with = "flexible_content")]
     content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl Message {
+    fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+/// Reads a string, null, or `[{"type": "text", "text": ...}]` blocks (non-text blocks are
+/// skipped) into one string.
+fn flexible_content<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Content {
+        Text(String),
+        Blocks(Vec<ContentBlock>),
+    }
+
+    #[derive(Deserialize)]
+    struct ContentBlock {
+        #[serde(rename = "type", default)]
+        kind: Option<String>,
+        #[serde(default)]
+        text: Option<String>,
+    }
+
+    Ok(match Option::<Content>::deserialize(deserializer)? {
+        None => String::new(),
+        Some(Content::Text(text)) => text,
+        Some(Content::Blocks(blocks)) => blocks
+            .into_iter()
+            .filter(|block| block.kind.as_deref().is_none_or(|kind| kind == "text"))
+            .filter_map(|block| block.text)
+            .collect(),
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FunctionCall {
+    name: String,
+    /// JSON-encoded arguments, as the API sends them.
+    arguments: String,
 }
 
 #[derive(Debug, Serialize)]
 struct ApiRequest {
     model: String,
     messages: Vec<Message>,
+    /// End-user identifier for the provider's abuse monitoring.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// Provider-specific parameters, serialized alongside the standard fields.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ApiResponse {
     choices: Vec<Choice>,
+    usage: Option<Usage>,
+    /// The model that actually answered, when the provider reports it.
+    #[serde(default)]
+    model: Option<String>,
+    /// Source URLs of a search-grounded reply, which cites them as `[1]`, `[2]`, ...
+    #[serde(default)]
+    citations: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Choice {
+    message: ResponseMessage,
+}
+
+/// A reply message along with the sources some providers annotate it with.
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    #[serde(flatten)]
     message: Message,
+    #[serde(default)]
+    annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Annotation {
+    /// Only `url_citation` annotations are read.
+    #[serde(default)]
+    url_citation: Option<Citation>,
 }
 
+/// A source cited by a reply, written into the chat as a footnote.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Citation {
+    url: String,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+/// One server-sent event of a streamed completion.
+#[derive(Debug, Deserialize)]
+struct StreamEvent {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only on the last event, and only when asked for with `stream_options`.
+    usage: Option<Usage>,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    #[serde(default)]
+    total_tokens: u64,
+}
+
+/// A reply from the model along with its token accounting, when the provider reports it.
 #[derive(Debug)]
-struct ChatContext {
-    max_messages: usize,
+struct Completion {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<Usage>,
+    model: String,
+    /// Sources from a web search, in the order the reply numbers them.
+    citations: Vec<Citation>,
 }
 
-impl ChatContext {
-    fn new(_content: String) -> Self {
-        Self {
-            max_messages: MAX_CONTEXT_MESSAGES,
-        }
+/// Why an API call failed, so callers can tell a rejected key from a timeout without
+/// matching on message text.
+#[derive(Debug, thiserror::Error)]
+enum ChatError {
+    /// The API rejected the key (401 or 403).
+    #[error("API key rejected: status {0}")]
+    Auth(reqwest::StatusCode),
+    /// Every API key is cooling down after a 429.
+    #[error("All API keys are rate limited, try again later")]
+    RateLimited,
+    /// The request never got an answer: no connection, a timeout or a dropped one.
+    #[error("Network error")]
+    Network(#[source] reqwest::Error),
+    /// The response body could not be read as a completion.
+    #[error("Malformed API response")]
+    Parse(#[source] reqwest::Error),
+    /// Any other unsuccessful status.
+    #[error("API error: status {0}")]
+    Status(reqwest::StatusCode),
+    /// The API kept answering with an empty `choices` array.
+    #[error("API returned no choices after {attempts} attempts")]
+    NoChoices { attempts: u32 },
+    /// A `DEEPSEEK_REQUEST_TRANSFORM` or `DEEPSEEK_RESPONSE_TRANSFORM` command failed.
+    #[error("Transform failed: {0:#}")]
+    Transform(anyhow::Error),
+    /// A streamed response stalled or sent an event that isn't a completion chunk.
+    #[error("Streaming failed: {0}")]
+    Stream(String),
+}
+
+impl ChatError {
+    fn is_offline(&self) -> bool {
+        matches!(self, Self::Network(e) if e.is_connect() || e.is_timeout() || is_connection_error(e))
     }
+}
 
-    fn parse_messages(&self, content: &str) -> Vec<Message> {
-        let parts: Vec<&str> = content.split(MESSAGE_SEPARATOR).collect();
-        let mut messages = Vec::with_capacity(parts.len());
+/// What gets POSTed to `CHAT_WEBHOOK_URL` after each turn.
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    file: String,
+    user: String,
+    assistant: String,
+    usage: Option<Usage>,
+    timestamp: u64,
+}
 
-        for (i, part) in parts.iter().enumerate() {
-            let part = part.trim();
-            if part.is_empty() {
-                continue;
-            }
+/// One turn's entry in the `<chat>.usage.json` sidecar.
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageRecord {
+    timestamp: u64,
+    model: String,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
 
-            let role = if i % 2 == 0 { "user" } else { "assistant" };
-            messages.push(Message {
-                role: role.to_string(),
-                content: part.to_string(),
-            });
+/// Command-line flags.
+#[derive(Debug, Default)]
+struct Args {
+    /// Exit non-zero on the first processing error instead of continuing to watch.
+    exit_on_error: bool,
+    /// Let the model run allow-listed commands through the `shell` tool.
+    allow_tools: bool,
+    /// Read a conversation from stdin, print the reply to stdout and exit.
+    once: bool,
+    /// Serve Prometheus metrics on this local port.
+    metrics_port: Option<u16>,
+    /// Rewrite this file's legacy separators to the current one and exit.
+    migrate_separator: Option<PathBuf>,
+    /// Fail at startup if the chat file is missing instead of creating it.
+    no_create: bool,
+    /// Download a conversation into the chat file before watching it.
+    from_url: Option<String>,
+    /// Send one tiny request to check the key and network, then exit.
+    check: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut args = Self::default();
+        let mut argv = std::env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--exit-on-error" => args.exit_on_error = true,
+                "--allow-tools" => args.allow_tools = true,
+                "--once" => args.once = true,
+                "--no-create" => args.no_create = true,
+                "--check" => args.check = true,
+                "--migrate-separator" => {
+                    let path = argv.next().context("--migrate-separator needs a file")?;
+                    args.migrate_separator = Some(path.into());
+                }
+                "--from-url" => {
+                    let url = argv.next().context("--from-url needs a URL")?;
+                    args.from_url = Some(parse_endpoint(&url)?);
+                }
+                "--metrics" => {
+                    let port = argv.next().context("--metrics needs a port")?;
+                    args.metrics_port = Some(port.parse().context("--metrics port must be a number")?);
+                }
+                other => anyhow::bail!("Unknown argument: {}", other),
+            }
         }
+        Ok(args)
+    }
+}
 
-        if messages.len() > self.max_messages {
-            messages[messages.len() - self.max_messages..].to_vec()
-        } else {
-            messages
+/// Settings read from the environment (and `.env`) at startup.
+#[derive(Debug)]
+struct Config {
+    /// Zeroed when dropped and redacted from `Debug`; exposed only to build request headers.
+    api_keys: Vec<SecretString>,
+    api_url: String,
+    model: String,
+    instructions: Option<String>,
+    developer_role: bool,
+    auto_title: bool,
+    title_model: Option<String>,
+    plain_text: bool,
+    fallback_model: Option<String>,
+    fallback_after: Duration,
+    /// Extra attempts when the API answers with an empty `choices` array.
+    empty_retries: u32,
+    /// Remaining requests at or below which the next request waits for the rate limit to
+    /// reset; 0 turns the wait off.
+    rate_limit_floor: u64,
+    /// Failed or retried API calls within `error_window` that pause sending until `/resume`.
+    error_budget: Option<usize>,
+    error_window: Duration,
+    share: Option<ShareTarget>,
+    /// Models `/bench` compares, on the configured endpoint.
+    bench_models: Vec<String>,
+    chat_file: PathBuf,
+    /// Set when `CHAT_FILE` is a URL: the conversation to download into `chat_file` at startup.
+    source_url: Option<String>,
+    reasoning_effort: Option<ReasoningEffort>,
+    reasoning_field: String,
+    user: Option<String>,
+    max_turns: Option<usize>,
+    webhook_url: Option<String>,
+    copy_reply: Option<CopyReply>,
+    /// USD per million prompt and completion tokens, for the cost column of `/usage`.
+    prices: Option<(f64, f64)>,
+    /// Directory of replies keyed by their context, served when the API is unreachable.
+    response_cache: Option<PathBuf>,
+    /// Open the assembled messages in `$EDITOR` before each send.
+    edit_before_send: bool,
+    /// Annotate each reply with an HTML comment naming the model and settings behind it.
+    reply_metadata: bool,
+    /// Dry-run ```` ```diff ```` blocks in replies against the chat's directory and note the result.
+    check_patches: bool,
+    /// Write replies to `<chat>.reply.md` and leave the watched file to the user.
+    reply_file: bool,
+    /// Re-read the file after each reply and check its turn structure.
+    verify_writes: Option<VerifyWrites>,
+    extra_body: serde_json::Map<String, serde_json::Value>,
+    redactions: Vec<regex::Regex>,
+    request_transform: Option<String>,
+    response_transform: Option<String>,
+    /// Keep the newest turn at the top of the file (`CHAT_ORDER=newest-first`).
+    newest_first: bool,
+    /// What follows each reply written into the chat.
+    reply_ending: ReplyEnding,
+    /// Pick up a corrected key from the env files after repeated auth failures.
+    reload_key: bool,
+    /// Stream replies into the file by default; `/stream` changes it for the session.
+    stream: bool,
+    /// Keep a hash chain of finished turns in `<chat>.sig` and warn when one changes.
+    sign: bool,
+    /// Snapshot the turn being typed to `<chat>.draft` and put it back after a crash.
+    save_draft: bool,
+    /// Wait for the chat's directory to come back after it is removed, instead of exiting
+    /// (`CHAT_ON_MISSING_DIR=wait`).
+    wait_for_dir: bool,
+    /// How user and assistant turns are written, from `CHAT_USER_WRAPPER` and
+    /// `CHAT_ASSISTANT_WRAPPER`; `None` writes them plain.
+    user_wrapper: Option<Wrapper>,
+    assistant_wrapper: Option<Wrapper>,
+    /// Tool definition for the provider's web search, offered on the turn after `/search`.
+    search_tool: Option<serde_json::Value>,
+    /// Header that carries the chat file's conversation ID on every request.
+    conversation_header: Option<String>,
+    /// Where each sent turn's trace is POSTed as OTLP/HTTP JSON.
+    otlp_url: Option<String>,
+    /// Headers sent with each trace, e.g. the collector's API key.
+    otlp_headers: Vec<(String, String)>,
+    /// `service.name` on exported traces.
+    service_name: String,
+    /// Keep a `<!-- status: ... -->` line at the top of the file showing what the monitor is doing.
+    status_line: bool,
+    memory_file: PathBuf,
+    /// While this file exists (relative to the chat's directory), nothing is sent.
+    pause_file: PathBuf,
+    /// Seed for a chat file created at startup.
+    template: Option<PathBuf>,
+    /// Tidy whitespace in the stored copy of each sent message.
+    normalize_user: bool,
+    /// Treat edits that only touch trailing spaces or blank lines as no change.
+    ignore_whitespace_edits: bool,
+    /// Addendum sent ahead of short or one-line-question messages, asking the model to
+    /// clarify before answering.
+    clarify_prompt: Option<String>,
+    clarify_max_chars: usize,
+    /// Estimated tokens a single `@include` may take before it is cut down to its head and tail.
+    include_budget: usize,
+    /// Prefix each `@include`d line with its line number in the file.
+    include_line_numbers: bool,
+    /// Blank lines that split the open turn into separate messages (`CHAT_AUTO_SEPARATOR`).
+    auto_separator: Option<usize>,
+    /// Merge separators with nothing but whitespace between them (`CHAT_COLLAPSE_SEPARATORS`).
+    collapse_separators: bool,
+    /// Messages with fewer characters or words than this wait instead of being sent.
+    min_chars: usize,
+    min_words: usize,
+    /// When set, only messages with a line starting with this word (e.g. `@ai`) are sent; the
+    /// rest are left in the file as notes.
+    trigger: Option<String>,
+    /// Where `/snippet <name>` lines look for `<name>.md`, relative to the chat's directory.
+    snippet_dir: PathBuf,
+    /// Write expanded snippets into chat.md instead of keeping the `/snippet` line there.
+    write_snippets: bool,
+    /// Characters of assembled context above which a note is written into the chat.
+    context_warn_chars: Option<usize>,
+    /// Changes to this many files in the chat's directory within `bulk_window` are taken
+    /// for a VCS operation and not sent; 0 turns the check off.
+    bulk_files: usize,
+    bulk_window: Duration,
+    /// Pause between receiving a reply and writing it, for demos or pacing turns.
+    reply_delay: Option<Duration>,
+    /// Programs the `shell` tool may run; empty disables tools.
+    tool_allowlist: Vec<String>,
+}
+
+impl Config {
+    /// The wrapper `role`'s turns are written with.
+    fn wrapper(&self, role: Role) -> Option<&Wrapper> {
+        match role {
+            Role::User => self.user_wrapper.as_ref(),
+            Role::Assistant => self.assistant_wrapper.as_ref(),
+            _ => None,
         }
     }
 
-    fn is_last_message_from_ai(&self, content: &str, cursor_pos: usize) -> bool {
-        // Get content up to cursor
-        let content_to_cursor = &content[..cursor_pos];
-        
-        // Find the last separator before cursor
-        if let Some(last_sep) = content_to_cursor.rfind(MESSAGE_SEPARATOR) {
-            // Get everything between the last separator and cursor
-            let after_sep = content_to_cursor[last_sep + MESSAGE_SEPARATOR.len()..].trim();
-            
-            // If there's no content after separator up to cursor, it was an AI message
-            // (because AI messages end with the separator)
-            after_sep.is_empty()
+    /// The file replies for `chat_path` are written to.
+    fn output_path(&self, chat_path: &Path) -> PathBuf {
+        if self.reply_file {
+            reply_path(chat_path)
         } else {
-            // If no separator found before cursor, it's a user message
-            false
+            chat_path.to_path_buf()
         }
     }
 
-    fn extract_new_message(&self, content: &str, cursor_pos: usize) -> String {
-        let content_to_cursor = &content[..cursor_pos];
-        
-        // Find the last separator before cursor
-        if let Some(last_sep) = content_to_cursor.rfind(MESSAGE_SEPARATOR) {
-            // Get everything after the last separator up to cursor
-            let message = content_to_cursor[last_sep + MESSAGE_SEPARATOR.len()..].trim();
-            if !message.is_empty() {
-                return message.to_string();
-            }
-            
-            // If empty after last separator, try to get the content before it
-            // (handles case where user is typing right after an AI message)
-            if let Some(second_last_sep) = content_to_cursor[..last_sep].rfind(MESSAGE_SEPARATOR) {
-                content_to_cursor[second_last_sep + MESSAGE_SEPARATOR.len()..last_sep].trim().to_string()
-            } else {
-                content_to_cursor[..last_sep].trim().to_string()
-            }
-        } else {
-            // No separator found, use all content up to cursor
-            content_to_cursor.trim().to_string()
+    fn from_env() -> Result<Self> {
+        let raw_keys = Zeroizing::new(
+            std::env::var("DEEPSEEK_API_KEYS")
+                .or_else(|_| std::env::var("DEEPSEEK_API_KEY"))
+                .context("DEEPSEEK_API_KEY not found")?,
+        );
+        let api_keys = parse_api_keys(&raw_keys);
+        if api_keys.is_empty() {
+            anyhow::bail!("DEEPSEEK_API_KEY is empty");
+        }
+
+        let api_url = match std::env::var("DEEPSEEK_API_URL") {
+            Ok(url) => parse_endpoint(&url)?,
+            Err(_) => DEFAULT_API_URL.to_string(),
+        };
+        let model = env_string("DEEPSEEK_MODEL").unwrap_or_else(|| DEFAULT_MODEL.to_string());
+        let fallback_model = env_string("DEEPSEEK_FALLBACK_MODEL");
+
+        let config = Self {
+            api_keys,
+            api_url,
+            model: model.clone(),
+            instructions: env_string("CHAT_SYSTEM_PROMPT"),
+            developer_role: env_flag("DEEPSEEK_DEVELOPER_ROLE")?,
+            auto_title: env_flag("CHAT_AUTO_TITLE")?,
+            title_model: env_string("CHAT_TITLE_MODEL"),
+            plain_text: env_flag("CHAT_PLAIN_TEXT")?,
+            fallback_model: fallback_model.clone(),
+            fallback_after: env_parse("DEEPSEEK_FALLBACK_AFTER_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_FALLBACK_AFTER),
+            empty_retries: env_parse("DEEPSEEK_EMPTY_RETRIES")?.unwrap_or(DEFAULT_EMPTY_RETRIES),
+            rate_limit_floor: env_parse("DEEPSEEK_RATE_LIMIT_FLOOR")?.unwrap_or(DEFAULT_RATE_LIMIT_FLOOR),
+            error_budget: env_parse("CHAT_ERROR_BUDGET")?,
+            error_window: env_parse("CHAT_ERROR_WINDOW_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_ERROR_WINDOW),
+            share: match (env_string("CHAT_SHARE_URL"), env_string("GITHUB_TOKEN")) {
+                (Some(url), _) => Some(ShareTarget::Paste {
+                    url: parse_endpoint(&url)?,
+                    token: env_string("CHAT_SHARE_TOKEN"),
+                }),
+                (None, Some(token)) => Some(ShareTarget::Gist { token }),
+                (None, None) => None,
+            },
+            bench_models: match env_string("CHAT_BENCH_MODELS") {
+                Some(list) => list
+                    .split(',')
+                    .map(|model| model.trim().to_string())
+                    .filter(|model| !model.is_empty())
+                    .collect(),
+                None => std::iter::once(model).chain(fallback_model).collect(),
+            },
+            chat_file: env_string("CHAT_FILE")
+                .filter(|file| !is_url(file))
+                .unwrap_or_else(|| CHAT_FILE.to_string())
+                .into(),
+            source_url: env_string("CHAT_FILE")
+                .filter(|file| is_url(file))
+                .map(|url| parse_endpoint(&url))
+                .transpose()?,
+            reasoning_effort: env_parse("DEEPSEEK_REASONING_EFFORT")?,
+            user: env_string("DEEPSEEK_USER"),
+            reasoning_field: env_string("DEEPSEEK_REASONING_FIELD")
+                .unwrap_or_else(|| DEFAULT_REASONING_FIELD.to_string()),
+            max_turns: env_parse("CHAT_MAX_TURNS")?,
+            webhook_url: env_string("CHAT_WEBHOOK_URL")
+                .map(|url| parse_endpoint(&url))
+                .transpose()?,
+            extra_body: env_string("DEEPSEEK_EXTRA_BODY")
+                .map(|json| parse_extra_body(&json))
+                .transpose()?
+                .unwrap_or_default(),
+            redactions: env_string("CHAT_REDACT_PATTERNS")
+                .map(|json| parse_redactions(&json))
+                .transpose()?
+                .unwrap_or_default(),
+            request_transform: env_string("DEEPSEEK_REQUEST_TRANSFORM"),
+            response_transform: env_string("DEEPSEEK_RESPONSE_TRANSFORM"),
+            newest_first: match env_string("CHAT_ORDER").as_deref() {
+                None | Some("oldest-first") => false,
+                Some("newest-first") => true,
+                Some(other) => anyhow::bail!("CHAT_ORDER must be oldest-first or newest-first, got {:?}", other),
+            },
+            reply_ending: env_string("CHAT_REPLY_ENDING")
+                .map(|value| value.parse())
+                .transpose()?
+                .unwrap_or(ReplyEnding::Separator),
+            status_line: env_flag("CHAT_STATUS_LINE")?,
+            reload_key: env_flag("CHAT_RELOAD_KEY")?,
+            stream: env_flag("CHAT_STREAM")?,
+            sign: env_flag("CHAT_SIGN")?,
+            save_draft: env_flag("CHAT_SAVE_DRAFT")?,
+            wait_for_dir: match env_string("CHAT_ON_MISSING_DIR").as_deref() {
+                None | Some("exit") => false,
+                Some("wait") => true,
+                Some(other) => anyhow::bail!("CHAT_ON_MISSING_DIR must be exit or wait, got {:?}", other),
+            },
+            user_wrapper: env_string("CHAT_USER_WRAPPER")
+                .map(|value| parse_wrapper("CHAT_USER_WRAPPER", &value))
+                .transpose()?
+                .flatten(),
+            assistant_wrapper: env_string("CHAT_ASSISTANT_WRAPPER")
+                .map(|value| parse_wrapper("CHAT_ASSISTANT_WRAPPER", &value))
+                .transpose()?
+                .flatten(),
+            search_tool: env_string("CHAT_SEARCH_TOOL")
+                .map(|json| parse_search_tool(&json))
+                .transpose()?,
+            conversation_header: env_string("CHAT_CONVERSATION_HEADER")
+                .map(|name| {
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                        .map(|_| name.clone())
+                        .with_context(|| format!("CHAT_CONVERSATION_HEADER is not a valid header name: {:?}", name))
+                })
+                .transpose()?,
+            otlp_url: match env_string("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+                Some(url) => Some(parse_endpoint(&url)?),
+                None => env_string("OTEL_EXPORTER_OTLP_ENDPOINT")
+                    .map(|url| parse_endpoint(&format!("{}/v1/traces", url.trim_end_matches('/'))))
+                    .transpose()?,
+            },
+            otlp_headers: otlp_headers()?,
+            service_name: env_string("OTEL_SERVICE_NAME").unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string()),
+            copy_reply: env_string("CHAT_COPY_REPLY")
+                .filter(|value| !matches!(value.as_str(), "0" | "false"))
+                .map(|value| value.parse())
+                .transpose()?,
+            prices: match (env_parse("CHAT_PRICE_PROMPT")?, env_parse("CHAT_PRICE_COMPLETION")?) {
+                (None, None) => None,
+                (prompt, completion) => Some((prompt.unwrap_or(0.0), completion.unwrap_or(0.0))),
+            },
+            response_cache: env_string("CHAT_RESPONSE_CACHE").map(PathBuf::from),
+            edit_before_send: env_flag("CHAT_EDIT_BEFORE_SEND")?,
+            reply_metadata: env_flag("CHAT_REPLY_METADATA")?,
+            check_patches: env_flag("CHAT_CHECK_PATCHES")?,
+            reply_file: env_flag("CHAT_REPLY_FILE")?,
+            verify_writes: env_string("CHAT_VERIFY_WRITES")
+                .filter(|value| !matches!(value.as_str(), "0" | "false"))
+                .map(|value| value.parse())
+                .transpose()?,
+            memory_file: env_string("CHAT_MEMORY_FILE")
+                .unwrap_or_else(|| MEMORY_FILE.to_string())
+                .into(),
+            pause_file: env_string("CHAT_PAUSE_FILE")
+                .unwrap_or_else(|| PAUSE_FILE.to_string())
+                .into(),
+            template: env_string("CHAT_TEMPLATE").map(PathBuf::from),
+            normalize_user: env_flag("CHAT_NORMALIZE_USER")?,
+            ignore_whitespace_edits: env_flag("CHAT_IGNORE_WHITESPACE_EDITS")?,
+            clarify_prompt: match env_string("CHAT_CLARIFY_PROMPT") {
+                Some(prompt) => Some(prompt),
+                None => env_flag("CHAT_CLARIFY")?.then(|| CLARIFY_PROMPT.to_string()),
+            },
+            clarify_max_chars: env_parse("CHAT_CLARIFY_MAX_CHARS")?.unwrap_or(DEFAULT_CLARIFY_MAX_CHARS),
+            include_budget: {
+                let context_tokens: usize = env_parse("CHAT_CONTEXT_TOKENS")?.unwrap_or(DEFAULT_CONTEXT_TOKENS);
+                let share: f64 = env_parse("CHAT_INCLUDE_SHARE")?.unwrap_or(DEFAULT_INCLUDE_SHARE);
+                if !(share > 0.0 && share <= 1.0) {
+                    anyhow::bail!("CHAT_INCLUDE_SHARE must be between 0 and 1, got {}", share);
+                }
+                (context_tokens as f64 * share) as usize
+            },
+            include_line_numbers: env_flag("CHAT_INCLUDE_LINE_NUMBERS")?,
+            auto_separator: match env_parse("CHAT_AUTO_SEPARATOR")? {
+                Some(0) => anyhow::bail!("CHAT_AUTO_SEPARATOR must be at least 1 blank line"),
+                gap => gap,
+            },
+            collapse_separators: env_flag("CHAT_COLLAPSE_SEPARATORS")?,
+            min_chars: env_parse("CHAT_MIN_CHARS")?.unwrap_or(0),
+            min_words: env_parse("CHAT_MIN_WORDS")?.unwrap_or(0),
+            trigger: env_string("CHAT_TRIGGER"),
+            snippet_dir: env_string("CHAT_SNIPPET_DIR")
+                .unwrap_or_else(|| SNIPPET_DIR.to_string())
+                .into(),
+            write_snippets: env_flag("CHAT_WRITE_SNIPPETS")?,
+            context_warn_chars: env_parse("CHAT_CONTEXT_WARN_CHARS")?,
+            bulk_files: env_parse("CHAT_BULK_FILES")?.unwrap_or(DEFAULT_BULK_FILES),
+            bulk_window: env_parse("CHAT_BULK_WINDOW_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BULK_WINDOW),
+            reply_delay: env_parse("CHAT_REPLY_DELAY_MS")?.map(Duration::from_millis),
+            tool_allowlist: env_string("CHAT_TOOL_ALLOWLIST")
+                .map(|list| {
+                    list.split(',')
+                        .map(|program| program.trim().to_string())
+                        .filter(|program| !program.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        if config.newest_first && config.reply_ending != ReplyEnding::Separator {
+            anyhow::bail!("CHAT_ORDER=newest-first needs CHAT_REPLY_ENDING=separator");
         }
+        Ok(config)
+    }
+
+    /// The clarifying-questions addendum, when enabled and `message` looks underspecified:
+    /// shorter than `clarify_max_chars`, or a single-line question.
+    fn clarify_addendum(&self, message: &str) -> Option<&str> {
+        let message = message.trim();
+        let ambiguous = message.chars().count() < self.clarify_max_chars
+            || (!message.contains('\n') && message.ends_with('?'));
+        self.clarify_prompt.as_deref().filter(|_| ambiguous)
     }
 }
 
-struct ApiClient {
-    client: reqwest::Client,
-    api_key: String,
+/// Reads an environment variable, treating unset and blank the same.
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
 }
 
-impl ApiClient {
-    fn new(api_key: String) -> Self {
-        Self {
-            client: reqwest::Client::builder()
-                .timeout(Duration::from_secs(30))
-                .build()
-                .expect("Failed to create HTTP client"),
-            api_key,
-        }
+fn env_parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    env_string(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} has an invalid value: {:?}", name, value))
+        })
+        .transpose()
+}
+
+fn env_flag(name: &str) -> Result<bool> {
+    match env_string(name).as_deref() {
+        None => Ok(false),
+        Some("1" | "true" | "yes" | "on") => Ok(true),
+        Some("0" | "false" | "no" | "off") => Ok(false),
+        Some(other) => anyhow::bail!("{} must be true or false, got {:?}", name, other),
     }
+}
 
-    async fn call_api(&self, messages: Vec<Message>) -> Result<String> {
-        let request = ApiRequest {
-            model: "deepseek-chat".to_string(),
-            messages,
-        };
+#[derive(Debug)]
+enum Command {
+    Endpoint(String),
+    Preview,
+    Undo,
+    Redo,
+    Plain(String),
+    Chats,
+    Switch(String),
+    Fork(String),
+    Summarize(String),
+    Lang(String),
+    Remember(String),
+    Bench,
+    DiffReplies(String),
+    Complete,
+    Share,
+    Timing(String),
+    Stream(String),
+    Search(String),
+    Replay,
+    Usage,
+    Pin(String),
+    Unpin(String),
+    Resume,
+    Normalize,
+    Trim(String),
+    Context(String),
+}
 
-        let response = self
-            .client
-            .post(API_URL)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await?;
+/// What a command does to the chat file once it has run.
+enum CommandOutcome {
+    /// Replace the command text with a note block.
+    Note(String),
+    /// Replace the command text with markdown in a scratch region, which renders but is never
+    /// sent.
+    Scratch(String),
+    /// Replace the whole file with new content.
+    Restore(String),
+}
+
+impl Command {
+    /// Recognizes a `/command [arg]` on the last line of a message, so a draft typed above it
+    /// is left alone. Anything else is a regular chat message.
+    fn parse(message: &str) -> Option<Self> {
+        let line = Self::line(message);
+        if !line.starts_with('/') {
+            return None;
+        }
+        let (name, arg) = line
+            .split_once(char::is_whitespace)
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((line, ""));
 
-        if !response.status().is_success() {
-            anyhow::bail!("API error: status {}", response.status());
+        match name {
+            "/endpoint" => Some(Self::Endpoint(arg.to_string())),
+            "/preview" => Some(Self::Preview),
+            "/undo" => Some(Self::Undo),
+            "/redo" => Some(Self::Redo),
+            "/plain" => Some(Self::Plain(arg.to_string())),
+            "/chats" => Some(Self::Chats),
+            "/switch" => Some(Self::Switch(arg.to_string())),
+            "/fork" => Some(Self::Fork(arg.to_string())),
+            "/summarize" => Some(Self::Summarize(arg.to_string())),
+            "/lang" => Some(Self::Lang(arg.to_string())),
+            "/remember" => Some(Self::Remember(arg.to_string())),
+            "/bench" => Some(Self::Bench),
+            "/diff-replies" => Some(Self::DiffReplies(arg.to_string())),
+            "/complete" => Some(Self::Complete),
+            "/share" => Some(Self::Share),
+            "/timing" => Some(Self::Timing(arg.to_string())),
+            "/stream" => Some(Self::Stream(arg.to_string())),
+            "/search" => Some(Self::Search(arg.to_string())),
+            "/replay" => Some(Self::Replay),
+            "/usage" => Some(Self::Usage),
+            "/pin" => Some(Self::Pin(arg.to_string())),
+            "/unpin" => Some(Self::Unpin(arg.to_string())),
+            "/resume" => Some(Self::Resume),
+            "/normalize" => Some(Self::Normalize),
+            "/trim" => Some(Self::Trim(arg.to_string())),
+            "/context" => Some(Self::Context(arg.to_string())),
+            _ => None,
         }
+    }
 
-        let api_resp: ApiResponse = response.json().await?;
-        api_resp
-            .choices
-            .first()
-            .map(|c| c.message.content.clone())
-            .context("No response from API")
+    fn line(message: &str) -> &str {
+        message.trim().lines().next_back().unwrap_or_default().trim()
     }
 }
 
-fn debug_log(message: &str) {
-    use colored::Colorize;
-    
-    let prefixes = [
-        ("error", ("❌", "red")),
-        ("skip", ("⏭️", "yellow")),
-        ("parse", ("🔍", "cyan")),
-        ("add", ("➕", "green")),
-        ("call", ("🌐", "blue")),
-        ("response", ("✉️", "magenta")),
-        ("detect", ("👀", "cyan")),
-        ("write", ("✍️", "green")),
-        ("init", ("🚀", "green")),
-        ("load", ("📂", "blue")),
-        ("trim", ("✂️", "yellow")),
-        ("unchanged", ("🔄", "yellow")),
-        ("monitoring", ("👁️", "cyan")),
-    ];
+/// Wraps tool output in a `chat-md` fenced block, which `strip_notes` removes before sending.
+fn note_block(body: &str) -> String {
+    fenced(NOTE_INFO, body)
+}
 
-    let (prefix, color) = prefixes
-        .iter()
-        .find(|(key, _)| message.to_lowercase().contains(key))
-        .map(|(_, (emoji, color))| (*emoji, *color))
-        .unwrap_or(("💬", "white"));
+fn scratch_block(body: &str) -> String {
+    format!("<!-- scratch -->\n{}\n<!-- /scratch -->", body.trim_end())
+}
 
-    let colored_message = match color {
-        "red" => message.red(),
-        "yellow" => message.yellow(),
-        "cyan" => message.cyan(),
-        "green" => message.green(),
-        "blue" => message.blue(),
-        "magenta" => message.magenta(),
-        _ => message.white(),
-    };
+/// A fenced code block longer than any backtick run in `body`, so it can't close early.
+fn fenced(info: &str, body: &str) -> String {
+    let longest_run = body.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_run.max(2) + 1);
+    format!("{}{}\n{}\n{}", fence, info, body.trim_end(), fence)
+}
+
+fn strip_notes(text: &str) -> String {
+    let mut kept = Vec::new();
+    let mut open_fence: Option<(char, usize)> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        let marker = fence_marker(trimmed);
+
+        match open_fence {
+            Some(open) => {
+                if closes_fence(trimmed, open) {
+                    open_fence = None;
+                }
+            }
+            None if marker.is_some_and(|(_, len)| trimmed[len..].trim() == NOTE_INFO) => {
+                open_fence = marker
+            }
+            None => kept.push(line),
+        }
+    }
 
-    println!("{} {}", prefix, colored_message);
+    kept.join("\n")
 }
 
-async fn process_new_messages(
-    content: String,
-    last_content: Arc<Mutex<String>>,
-    api_client: Arc<ApiClient>,
-    chat_context: Arc<Mutex<ChatContext>>,
-) -> Result<()> {
-    let mut last_content = last_content.lock().unwrap();
-    
-    if content == *last_content {
-        debug_log("unchanged: no new content");
-        return Ok(());
+/// Byte ranges of `<!-- scratch -->` ... `<!-- /scratch -->` regions, markers included. An
+/// unclosed region runs to the end of the text.
+fn scratch_ranges(text: &str) -> Vec<std::ops::Range<usize>> {
+    static SCRATCH: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let scratch = SCRATCH.get_or_init(|| {
+        regex::Regex::new(r"(?s)<!--\s*scratch\s*-->.*?(?:<!--\s*/scratch\s*-->|\z)").expect("valid scratch pattern")
+    });
+    scratch.find_iter(text).map(|found| found.range()).collect()
+}
+
+/// `text` without its scratch regions, which are never sent and never trigger a send.
+fn strip_scratch(text: &str) -> String {
+    let mut kept = String::with_capacity(text.len());
+    let mut last = 0;
+    for range in scratch_ranges(text) {
+        kept.push_str(&text[last..range.start]);
+        last = range.end;
     }
+    kept.push_str(&text[last..]);
+    kept
+}
 
-    if !content.ends_with(DOUBLE_NEWLINE) {
-        debug_log("skip: waiting for double enter");
-        *last_content = content;
-        return Ok(());
+/// What gets sent for a turn: notes removed, trimmed, without the `wrapper` its role is
+/// written with, and without a `### user:name` author header, which only labels who wrote
+/// the turn in shared files.
+fn message_text(text: &str, wrapper: Option<&Wrapper>) -> String {
+    let text = strip_pin(&strip_metadata(&strip_notes(&strip_scratch(text))));
+    // Notes inside a blockquote wrapper only read as notes once it is off
+    let text = match wrapper.and_then(|wrapper| wrapper.unwrap(&text)) {
+        Some(inner) => strip_notes(&inner),
+        None => text,
+    };
+    let text = strip_timing(text.trim());
+    let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+    match first.trim().strip_prefix("### user:") {
+        Some(author) if !author.trim().is_empty() => rest.trim().to_string(),
+        _ => text.to_string(),
     }
+}
 
-    let cursor_pos = content
-        .rfind(DOUBLE_NEWLINE)
-        .context("Invalid content format")?;
+/// `message` with the `CHAT_TRIGGER` word removed from the first line that starts with it, or
+/// `None` when no line does. The word must stand alone, so `@ai` doesn't match `@aidan`.
+fn strip_trigger(message: &str, trigger: &str) -> Option<String> {
+    let mut found = false;
+    let lines: Vec<&str> = message
+        .lines()
+        .map(|line| {
+            if found {
+                return line;
+            }
+            let rest = line.trim_start().strip_prefix(trigger);
+            match rest {
+                Some(rest) if rest.is_empty() || rest.starts_with([' ', '\t', ':', ',']) => {
+                    found = true;
+                    rest.trim_start_matches([':', ',']).trim_start()
+                }
+                _ => line,
+            }
+        })
+        .collect();
+    found.then(|| lines.join("\n").trim().to_string())
+}
 
-    let chat_context = chat_context.lock().unwrap();
-    
-    if chat_context.is_last_message_from_ai(&content, cursor_pos) {
-        debug_log("skip: last message was from AI");
-        *last_content = content.clone();
-        return Ok(());
+/// `<!-- model=deepseek-chat temp=0.7 tokens=812 -->`: which model answered, the sampling
+/// settings from `DEEPSEEK_EXTRA_BODY` that were sent, and the reply's token count.
+fn reply_metadata(completion: &Completion, extra_body: &serde_json::Map<String, serde_json::Value>) -> String {
+    let mut fields = vec![format!("model={}", completion.model)];
+    for (key, name) in [("temperature", "temp"), ("top_p", "top_p"), ("seed", "seed")] {
+        if let Some(value) = extra_body.get(key) {
+            fields.push(format!("{}={}", name, value));
+        }
     }
-
-    let message_content = chat_context.extract_new_message(&content, cursor_pos);
-    if message_content.is_empty() {
-        debug_log("skip: empty message");
-        *last_content = content;
-        return Ok(());
+    if let Some(usage) = &completion.usage {
+        fields.push(format!("tokens={}", usage.completion_tokens));
     }
+    format!("<!-- {} -->", fields.join(" "))
+}
 
-    let mut messages = if let Some(last_sep_idx) = content[..cursor_pos].rfind(MESSAGE_SEPARATOR) {
-        let prev_content = &content[..last_sep_idx];
-        chat_context.parse_messages(prev_content)
-    } else {
-        Vec::new()
-    };
+/// Drops `CHAT_REPLY_METADATA` comments, which are for the file's reader, not the model.
+fn strip_metadata(text: &str) -> String {
+    static METADATA: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let metadata = METADATA
+        .get_or_init(|| regex::Regex::new(r"\n*<!-- model=[^>]*-->").expect("valid metadata pattern"));
+    metadata.replace_all(text, "").into_owned()
+}
 
-    messages.push(Message {
-        role: "user".to_string(),
-        content: message_content.clone(),
-    });
+fn pin_pattern() -> &'static regex::Regex {
+    static PIN: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    PIN.get_or_init(|| regex::Regex::new(r"\n*<!-- pin -->").expect("valid pin pattern"))
+}
 
-    debug_log(&format!("parse: sending message: {:?}", message_content));
+/// Whether a turn carries the `/pin` marker, which keeps it in context however old it is.
+fn is_pinned(text: &str) -> bool {
+    pin_pattern().is_match(text)
+}
 
-    // Call API
-    debug_log(&format!("call: sending request with {} messages", messages.len()));
-    let response = api_client.call_api(messages).await?;
+fn strip_pin(text: &str) -> String {
+    pin_pattern().replace_all(text, "").into_owned()
+}
 
-    // Append response
-    debug_log("write: adding assistant response");
-    let response_text = format!("\n{}{}", response, MESSAGE_SEPARATOR);
-    fs::write(CHAT_FILE, format!("{}{}", content, response_text)).await?;
+/// Adds or removes the pin marker on message `turn` (counted like `/fork at=N`), or by
+/// default the last message before the open turn. Returns the new content and the message
+/// number.
+fn set_pin(content: &str, turn: Option<usize>, pinned: bool) -> Result<(String, usize)> {
+    let turns = split_turns(content);
+    let messages: Vec<&Turn> = turns
+        .iter()
+        .filter(|turn| turn.role != Role::System && !strip_notes(turn.text).trim().is_empty())
+        .collect();
+    let open_start = turns.last().map_or(0, |turn| turn.start);
+    let number = match turn {
+        Some(n) if (1..=messages.len()).contains(&n) => n,
+        Some(_) => anyhow::bail!("Turn must be between 1 and {}", messages.len()),
+        None => messages
+            .iter()
+            .rposition(|turn| turn.start < open_start)
+            .map(|i| i + 1)
+            .context("No earlier turn to pin")?,
+    };
 
-    *last_content = fs::read_to_string(CHAT_FILE).await?;
-    Ok(())
+    let target = messages[number - 1];
+    let text = strip_pin(target.text.trim_end());
+    let trailing = &target.text[target.text.trim_end().len()..];
+    let marker = if pinned { "\n\n<!-- pin -->" } else { "" };
+    let end = target.start + target.text.len();
+    Ok((
+        format!("{}{}{}{}{}", &content[..target.start], text, marker, trailing, &content[end..]),
+        number,
+    ))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok();
+/// Drops a trailing `/timing` footnote such as `_(2.4s, 812 tokens)_`.
+fn strip_timing(text: &str) -> &str {
+    static TIMING: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let timing = TIMING.get_or_init(|| {
+        regex::Regex::new(r"\n*_\(\d+(?:\.\d+)?s(?:, \d+ tokens)?\)_\z").expect("valid timing pattern")
+    });
+    match timing.find(text) {
+        Some(found) => text[..found.start()].trim_end(),
+        None => text,
+    }
+}
 
-    let api_key = std::env::var("DEEPSEEK_API_KEY").context("DEEPSEEK_API_KEY not found")?;
-    let initial_content = fs::read_to_string(CHAT_FILE).await.unwrap_or_default();
+/// The fence character and length if `line` (already trimmed) opens or closes a code fence.
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let fence_char = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+    let len = line.chars().take_while(|&c| c == fence_char).count();
+    (len >= 3).then_some((fence_char, len))
+}
 
-    let api_client = Arc::new(ApiClient::new(api_key));
-    let chat_context = Arc::new(Mutex::new(ChatContext::new(initial_content.clone())));
-    let last_content = Arc::new(Mutex::new(initial_content));
+fn closes_fence(line: &str, (fence_char, len): (char, usize)) -> bool {
+    fence_marker(line).is_some_and(|(c, n)| c == fence_char && n >= len && n == line.len())
+}
 
-    let (tx, mut rx) = mpsc::channel(10);
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
+/// Parses an `on`/`off` command argument; an empty argument means "just report".
+fn parse_toggle(arg: &str) -> Result<Option<bool>> {
+    match arg {
+        "" => Ok(None),
+        "on" => Ok(Some(true)),
+        "off" => Ok(Some(false)),
+        other => anyhow::bail!("Expected on or off, got {:?}", other),
+    }
+}
 
-    let mut watcher = RecommendedWatcher::new(
-        move |res: Result<Event, notify::Error>| {
-            if let Ok(event) = res {
-                if event.kind.is_modify() {
-                    let _ = tx.blocking_send(());
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Reduces markdown to its text, dropping link targets, emphasis and table syntax.
+/// Code is kept verbatim since it usually matters to the question.
+fn markdown_to_plain(markdown: &str) -> String {
+    use pulldown_cmark::{Event, Options, Parser, TagEnd};
+
+    let mut plain = String::with_capacity(markdown.len());
+    for event in Parser::new_ext(markdown, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Text(text) | Event::Code(text) => plain.push_str(&text),
+            Event::SoftBreak => plain.push(' '),
+            Event::HardBreak => plain.push('\n'),
+            Event::End(TagEnd::TableCell) => plain.push(' '),
+            Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::CodeBlock
+                | TagEnd::TableHead
+                | TagEnd::TableRow,
+            ) => {
+                plain.truncate(plain.trim_end_matches(' ').len());
+                if !plain.ends_with('\n') {
+                    plain.push('\n');
                 }
             }
-        },
-        Config::default(),
-    )?;
-
-    watcher.watch(Path::new(CHAT_FILE).as_ref(), RecursiveMode::NonRecursive)?;
+            _ => {}
+        }
+    }
+    plain.trim().to_string()
+}
 
-    debug_log("init: chat monitor started");
-    println!("Monitoring chat.md for new messages...");
-    println!("Type your message and press Enter twice to send.");
+/// Contents of each fenced or indented code block in `markdown`, in order.
+fn code_blocks(markdown: &str) -> Vec<String> {
+    use pulldown_cmark::{Event, Parser, Tag, TagEnd};
 
-    let mut last_event_time = Instant::now();
-    while running.load(Ordering::SeqCst) {
-        tokio::select! {
-            Some(()) = rx.recv() => {
-                if last_event_time.elapsed() < Duration::from_millis(50) {
-                    continue;
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => current = Some(String::new()),
+            Event::Text(text) => {
+                if let Some(block) = &mut current {
+                    block.push_str(&text);
                 }
-                last_event_time = Instant::now();
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
 
-                debug_log("detect: file change");
-                let content = fs::read_to_string(CHAT_FILE).await?;
-                if let Err(e) = process_new_messages(
-                    content,
-                    last_content.clone(),
-                    api_client.clone(),
-                    chat_context.clone(),
-                ).await {
-                    debug_log(&format!("error: {}", e));
-                }
+/// Contents of each code block fenced as `diff` or `patch` in `markdown`, in order.
+fn diff_blocks(markdown: &str) -> Vec<String> {
+    use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, TagEnd};
+
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))
+                if matches!(info.split_whitespace().next(), Some("diff" | "patch")) =>
+            {
+                current = Some(String::new())
             }
-            _ = tokio::signal::ctrl_c() => {
-                debug_log("Shutting down...");
-                running_clone.store(false, Ordering::SeqCst);
-                break;
+            Event::Text(text) => {
+                if let Some(block) = &mut current {
+                    block.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+/// A command for `program` that doesn't inherit the API keys, since tools, transforms,
+/// `$EDITOR` and git have no use for them.
+fn subprocess(program: &str) -> tokio::process::Command {
+    let mut command = tokio::process::Command::new(program);
+    command.env_remove("DEEPSEEK_API_KEYS").env_remove("DEEPSEEK_API_KEY");
+    command
+}
+
+/// Whether `patch` would apply to the files under `dir`, via `git apply --check`; nothing
+/// is changed. Describes the conflict when it wouldn't.
+async fn check_patch(patch: &str, dir: &Path) -> String {
+    let result = async {
+        let child = subprocess("git")
+            .args(["apply", "--check", "--recount", "-"])
+            .current_dir(dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to run git apply")?;
+        let (output, written) = feed_child(child, patch.as_bytes().to_vec())
+            .await
+            .context("git apply timed out")?;
+        let output = output?;
+        if !output.status.success() {
+            return Ok(Some(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        written.context("Failed to write the patch to git apply")?;
+        anyhow::Ok(None)
+    };
+    match result.await {
+        Ok(None) => "✓ applies".to_string(),
+        Ok(Some(conflict)) => format!("✗ does not apply: {}", conflict),
+        Err(e) => format!("not checked: {:#}", e),
+    }
+}
+
+/// Parses extra request fields; they must form an object and can't replace the conversation.
+fn parse_extra_body(json: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).context("DEEPSEEK_EXTRA_BODY is not valid JSON")?;
+    let serde_json::Value::Object(fields) = value else {
+        anyhow::bail!("DEEPSEEK_EXTRA_BODY must be a JSON object, got {}", value);
+    };
+    if let Some(key) = ["model", "messages", "user"].iter().find(|key| fields.contains_key(**key)) {
+        anyhow::bail!("DEEPSEEK_EXTRA_BODY cannot set {:?}", key);
+    }
+    Ok(fields)
+}
+
+/// Parses the web-search tool definition, a JSON object such as `{"type": "web_search"}`.
+fn parse_search_tool(json: &str) -> Result<serde_json::Value> {
+    let tool: serde_json::Value = serde_json::from_str(json).context("CHAT_SEARCH_TOOL is not valid JSON")?;
+    if !tool.is_object() {
+        anyhow::bail!("CHAT_SEARCH_TOOL must be a JSON object, got {}", tool);
+    }
+    Ok(tool)
+}
+
+/// Parses a JSON array of regular expressions, e.g. `["sk-[A-Za-z0-9]+"]`.
+fn parse_redactions(json: &str) -> Result<Vec<regex::Regex>> {
+    let patterns: Vec<String> = serde_json::from_str(json)
+        .context("CHAT_REDACT_PATTERNS must be a JSON array of strings")?;
+    patterns
+        .iter()
+        .map(|pattern| {
+            regex::Regex::new(pattern).with_context(|| format!("Invalid redaction pattern {:?}", pattern))
+        })
+        .collect()
+}
+
+fn is_url(value: &str) -> bool {
+    value.starts_with("http://") || value.starts_with("https://")
+}
+
+/// Trace export headers from `OTEL_EXPORTER_OTLP_HEADERS` and then
+/// `OTEL_EXPORTER_OTLP_TRACES_HEADERS`, which wins for a header both set.
+fn otlp_headers() -> Result<Vec<(String, String)>> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for var in ["OTEL_EXPORTER_OTLP_HEADERS", "OTEL_EXPORTER_OTLP_TRACES_HEADERS"] {
+        for (name, value) in parse_otlp_headers(var, &env_string(var).unwrap_or_default())? {
+            headers.retain(|(existing, _)| *existing != name);
+            headers.push((name, value));
+        }
+    }
+    Ok(headers)
+}
+
+/// Parses a comma-separated `key=value` list with percent-encoded values, the format of the
+/// `OTEL_EXPORTER_OTLP_*HEADERS` variables.
+fn parse_otlp_headers(var: &str, list: &str) -> Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    for pair in list.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (name, value) = pair
+            .split_once('=')
+            .with_context(|| format!("{} entries must be key=value, got {:?}", var, pair.trim()))?;
+        let name = name.trim().to_ascii_lowercase();
+        let value = percent_decode(value.trim());
+        reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("{} is not a valid header name: {:?}", var, name))?;
+        reqwest::header::HeaderValue::from_str(&value)
+            .with_context(|| format!("{} has an invalid value for {}", var, name))?;
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+/// Decodes `%XX` escapes, keeping anything that isn't one as written.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3).filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit));
+        match escape {
+            Some(hex) => {
+                let hex = std::str::from_utf8(hex).expect("hex digits are ASCII");
+                decoded.push(u8::from_str_radix(hex, 16).expect("checked to be hex digits"));
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_endpoint(url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url.trim())
+        .with_context(|| format!("Invalid endpoint URL: {}", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("Endpoint must be an http(s) URL: {}", url);
+    }
+    Ok(parsed.to_string())
+}
+
+/// Splits a leading YAML front matter block (`---` ... `---`) from the conversation body.
+fn split_front_matter(content: &str) -> (&str, &str) {
+    if let Some(rest) = content.strip_prefix("---\n") {
+        if let Some(end) = rest.find("\n---\n") {
+            let split = "---\n".len() + end + "\n---\n".len();
+            return (&content[..split], &content[split..]);
+        }
+    }
+    ("", content)
+}
+
+fn front_matter_title(content: &str) -> Option<&str> {
+    let (front_matter, _) = split_front_matter(content);
+    front_matter
+        .lines()
+        .find_map(|line| line.strip_prefix("title:"))
+        .map(|title| title.trim().trim_matches('"'))
+        .filter(|title| !title.is_empty())
+}
+
+/// Adds `title` to the front matter, creating the block if the file has none.
+fn with_title(content: &str, title: &str) -> String {
+    let escaped = title.replace('\\', "\\\\").replace('"', "\\\"");
+    let line = format!("title: \"{}\"\n", escaped);
+    if split_front_matter(content).0.is_empty() {
+        format!("---\n{}---\n{}", line, content)
+    } else {
+        format!("---\n{}{}", line, &content["---\n".len()..])
+    }
+}
+
+/// A raw stretch of the conversation between separators.
+#[derive(Debug)]
+struct Turn<'a> {
+    /// Role implied by the turn's position.
+    role: Role,
+    text: &'a str,
+    /// Byte offset of `text` in the content that was split.
+    start: usize,
+}
+
+/// Set once at startup from `CHAT_SEPARATOR`.
+static SEPARATOR: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+/// Older separator lines from `CHAT_LEGACY_SEPARATORS`, still recognized when reading.
+static LEGACY_SEPARATORS: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+
+fn status_line_pattern() -> &'static regex::Regex {
+    static STATUS_LINE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    STATUS_LINE.get_or_init(|| regex::Regex::new(r"\A<!-- status:[^\n]*-->\n?").expect("valid status pattern"))
+}
+
+/// The `CHAT_STATUS_LINE` line for `status`.
+fn status_line(status: &str) -> String {
+    // An error message must not close the comment early
+    format!("<!-- status: {} -->\n", status.replace("-->", "->"))
+}
+
+/// The status line below the front matter of `content`, if it has one.
+fn find_status_line(content: &str) -> Option<&str> {
+    let (_, body) = split_front_matter(content);
+    status_line_pattern().find(body).map(|found| found.as_str())
+}
+
+/// Drops the status line below the front matter; it is the monitor's, not the conversation's.
+fn without_status_line(content: String) -> String {
+    let (front_matter, body) = split_front_matter(&content);
+    match status_line_pattern().find(body) {
+        Some(found) => format!("{}{}", front_matter, &body[found.end()..]),
+        None => content,
+    }
+}
+
+/// `content`, which has no status line, with `line` below its front matter.
+fn with_status_line(content: String, line: Option<&str>) -> String {
+    let Some(line) = line else {
+        return content;
+    };
+    let (front_matter, body) = split_front_matter(&content);
+    format!("{}{}\n{}", front_matter, line.trim_end(), body)
+}
+
+/// The status line of the file at `path`, looked for in its first `STATUS_HEAD_BYTES` only.
+async fn current_status_line(path: &Path) -> Option<String> {
+    let file = fs::File::open(path).await.ok()?;
+    let mut head = Vec::new();
+    file.take(STATUS_HEAD_BYTES).read_to_end(&mut head).await.ok()?;
+    find_status_line(&String::from_utf8_lossy(&head)).map(str::to_string)
+}
+
+/// Rewrites the status line at the top of `path`, below any front matter, to `status` in one
+/// atomic replace. Left alone when it already says so, and while the file has edits the
+/// monitor hasn't handled yet (it differs from `seen`, what the monitor last read or wrote):
+/// rewriting then would race the editor, and the next event updates it anyway. Does nothing
+/// while `CHAT_STATUS_LINE` is off.
+async fn set_status(config: &Config, path: &Path, status: &str, seen: Option<&str>) {
+    if !config.status_line {
+        return;
+    }
+    let Ok(raw) = fs::read_to_string(path).await else {
+        return;
+    };
+    let line = status_line(status);
+    if find_status_line(&raw) == Some(line.as_str()) {
+        return;
+    }
+    let raw = without_status_line(raw);
+    if seen.is_some_and(|seen| from_file_order(raw.clone(), config.newest_first) != seen) {
+        debug_log("skip: file has unhandled edits, leaving the status line for the next event");
+        return;
+    }
+    if let Err(e) = replace_file(path, &with_status_line(raw, Some(&line))).await {
+        debug_log(&format!("error: could not update the status line: {}", e));
+    }
+}
+
+/// Chat file content in processing (oldest-first) order, without the status line. With
+/// `CHAT_ORDER=newest-first` the file holds turns newest first, and is turned around on every
+/// read and write so everything else sees it oldest first.
+fn from_file_order(content: String, newest_first: bool) -> String {
+    let content = without_status_line(content);
+    if !newest_first {
+        return content;
+    }
+    let turns = split_turns(&content);
+    if turns.len() < 2 {
+        return content;
+    }
+    // The message being typed is on top; its trailing blank lines are the send trigger, so
+    // they move to the end (less the newline that ended its line before the separator)
+    let top = turns[0].text;
+    let trailing = &top[top.trim_end().len()..];
+    let trailing = trailing.strip_suffix('\n').unwrap_or(trailing);
+    let (front_matter, _) = split_front_matter(&content);
+    let segments: Vec<&str> = turns.iter().rev().map(|turn| turn.text.trim()).collect();
+    format!("{}{}{}", front_matter, segments.join(separator()), trailing)
+}
+
+/// The inverse of `from_file_order`: how content is laid out in the file.
+fn to_file_order(content: &str, newest_first: bool) -> String {
+    if !newest_first {
+        return content.to_string();
+    }
+    let turns = split_turns(content);
+    if turns.len() < 2 {
+        return content.to_string();
+    }
+    let open = turns[turns.len() - 1].text;
+    let trailing = &open[open.trim_end().len()..];
+    let (front_matter, _) = split_front_matter(content);
+    let mut segments: Vec<String> = turns.iter().rev().map(|turn| turn.text.trim().to_string()).collect();
+    segments[0].push_str(trailing);
+    let joined = segments.join(separator());
+    // With nothing typed yet, start the file with the separator line itself
+    let joined = if segments[0].is_empty() { joined.trim_start_matches('\n') } else { &joined };
+    format!("{}{}\n", front_matter, joined)
+}
+
+async fn read_chat_file(path: &Path, newest_first: bool) -> Result<String> {
+    Ok(from_file_order(fs::read_to_string(path).await?, newest_first))
+}
+
+/// The message separator, always a single line with a newline on each side.
+fn separator() -> &'static str {
+    SEPARATOR.get().map(String::as_str).unwrap_or(MESSAGE_SEPARATOR)
+}
+
+/// Whether a trimmed line splits messages: the current separator or a legacy one.
+fn is_separator_line(line: &str) -> bool {
+    line == separator().trim()
+        || LEGACY_SEPARATORS
+            .get()
+            .is_some_and(|legacy| legacy.iter().any(|sep| sep == line))
+}
+
+/// Checks that a separator can be matched as a line of its own and returns that line.
+fn separator_line<'a>(name: &str, raw: &'a str) -> Result<&'a str> {
+    let line = raw.trim();
+    if line.is_empty() {
+        anyhow::bail!("{} must not be empty or whitespace", name);
+    }
+    if line.contains('\n') {
+        anyhow::bail!("{} must be a single line, got {:?}", name, raw);
+    }
+    if line == "---" {
+        anyhow::bail!("{} cannot be `---`, which delimits front matter", name);
+    }
+    Ok(line)
+}
+
+/// Checks a configured separator and puts it on its own line, the only way it is matched.
+fn parse_separator(raw: &str) -> Result<String> {
+    let line = separator_line("CHAT_SEPARATOR", raw)?;
+    if let Some(conflict) = markdown_conflict(line) {
+        debug_log(&format!(
+            "skip: CHAT_SEPARATOR {:?} also reads as {} in markdown; such lines in a reply will split it",
+            line, conflict
+        ));
+    }
+    Ok(format!("\n{}\n", line))
+}
+
+/// What markdown makes of `line` under a paragraph, when that is more than a rule or text.
+fn markdown_conflict(line: &str) -> Option<&'static str> {
+    use pulldown_cmark::{Event, Parser, Tag};
+
+    Parser::new(&format!("text\n{}\n", line)).find_map(|event| match event {
+        Event::Start(Tag::Heading { .. }) => Some("a heading"),
+        Event::Start(Tag::List(_)) => Some("a list"),
+        Event::Start(Tag::BlockQuote(_)) => Some("a blockquote"),
+        Event::Start(Tag::CodeBlock(_)) => Some("a code fence"),
+        _ => None,
+    })
+}
+
+/// Sets the separators every turn split uses from `CHAT_SEPARATOR` and
+/// `CHAT_LEGACY_SEPARATORS`.
+fn init_separators() -> Result<()> {
+    let separator = match std::env::var("CHAT_SEPARATOR") {
+        Ok(raw) => parse_separator(&raw)?,
+        Err(_) => MESSAGE_SEPARATOR.to_string(),
+    };
+    let legacy = env_string("CHAT_LEGACY_SEPARATORS")
+        .map(|list| {
+            list.split(',')
+                .map(|sep| separator_line("CHAT_LEGACY_SEPARATORS", sep).map(str::to_string))
+                .collect::<Result<_>>()
+        })
+        .transpose()?
+        .unwrap_or_default();
+    debug_log(&format!("init: message separator {:?}", separator));
+    SEPARATOR.get_or_init(|| separator);
+    LEGACY_SEPARATORS.get_or_init(|| legacy);
+    Ok(())
+}
+
+/// Splits the conversation body on separator lines, ignoring any inside fenced code blocks.
+/// Empty turns are kept so callers can preserve alternation when rewriting the file.
+fn split_turns(content: &str) -> Vec<Turn<'_>> {
+    let (front_matter, _) = split_front_matter(content);
+    let bounds = turn_bounds(content, front_matter.len());
+
+    // A system block at the top sits outside the user/assistant alternation
+    let has_system = bounds
+        .first()
+        .is_some_and(|&(start, end)| system_text(&content[start..end]).is_some());
+    bounds
+        .into_iter()
+        .enumerate()
+        .map(|(i, (start, end))| Turn {
+            role: match (has_system, i) {
+                (true, 0) => Role::System,
+                (true, i) if i % 2 == 1 => Role::User,
+                (false, i) if i % 2 == 0 => Role::User,
+                _ => Role::Assistant,
+            },
+            text: &content[start..end],
+            start,
+        })
+        .collect()
+}
+
+/// Byte ranges of the turns from `start`, which must be the start of a turn, to the end.
+fn turn_bounds(content: &str, mut start: usize) -> Vec<(usize, usize)> {
+    let mut bounds = Vec::new();
+    let mut offset = start;
+    let mut open_fence: Option<(char, usize)> = None;
+
+    for line in content[start..].split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        match open_fence {
+            Some(open) => {
+                if closes_fence(trimmed, open) {
+                    open_fence = None;
+                }
+            }
+            None if is_separator_line(trimmed) => {
+                bounds.push((start, line_start));
+                start = offset;
+            }
+            None => open_fence = fence_marker(trimmed),
+        }
+    }
+    bounds.push((start, content.len()));
+    bounds
+}
+
+/// Instructions from a first segment written as `> SYSTEM: ...` (a blockquote) or as a
+/// single ```` ```system ```` fenced block.
+fn system_text(text: &str) -> Option<String> {
+    let text = text.trim();
+    if let Some(quoted) = text.strip_prefix("> SYSTEM:") {
+        let lines: Vec<&str> = quoted
+            .lines()
+            .map(|line| line.trim_start().strip_prefix('>').unwrap_or(line).trim())
+            .collect();
+        return Some(lines.join("\n").trim().to_string());
+    }
+
+    let (first, rest) = text.split_once('\n')?;
+    let (fence_char, len) = fence_marker(first.trim())?;
+    if first.trim()[len..].trim() != "system" {
+        return None;
+    }
+    let (body, closing) = rest.rsplit_once('\n').unwrap_or(("", rest));
+    closes_fence(closing.trim(), (fence_char, len)).then(|| body.trim().to_string())
+}
+
+/// Number of non-empty messages in the conversation.
+fn turn_count(content: &str) -> usize {
+    split_turns(content)
+        .iter()
+        .filter(|turn| turn.role != Role::System && !strip_notes(turn.text).trim().is_empty())
+        .count()
+}
+
+/// Copies the front matter and the first `turns` non-empty messages (all if `None`), ready
+/// for the user to continue typing.
+fn conversation_prefix(content: &str, turns: Option<usize>) -> Result<String> {
+    let (front_matter, _) = split_front_matter(content);
+    let segments = split_turns(content);
+    let available = turn_count(content);
+    let turns = turns.unwrap_or(available);
+    if turns == 0 || turns > available {
+        anyhow::bail!("Turn must be between 1 and {}", available);
+    }
+
+    let mut kept = Vec::new();
+    let mut last_role = Role::User;
+    let mut count = 0;
+    for turn in segments {
+        let part = strip_notes(turn.text);
+        let part = part.trim();
+        kept.push(part.to_string());
+        if !part.is_empty() && turn.role != Role::System {
+            count += 1;
+            last_role = turn.role;
+            if count == turns {
+                break;
+            }
+        }
+    }
+
+    // After an assistant reply, open a new user turn; after a user turn, leave it open
+    let ending = if last_role == Role::Assistant { separator() } else { "\n" };
+    Ok(format!("{}{}{}", front_matter, kept.join(separator()), ending))
+}
+
+#[derive(Debug)]
+struct ChatContext {
+    /// The chat file currently being watched.
+    path: PathBuf,
+    /// How many recent turns are sent, changed with `/context`.
+    max_messages: usize,
+    instructions: Option<String>,
+    /// Send user turns as plain text instead of markdown.
+    plain_text: bool,
+    /// States undone with `/undo`, paired with the backup they replaced, newest last.
+    redo_stack: Vec<(String, String)>,
+    /// Language replies should be written in, set with `/lang`.
+    language: Option<String>,
+    /// Long-term memory file, relative to the chat's directory; `/remember` appends to it.
+    memory_file: PathBuf,
+    /// Its contents as of the current turn.
+    memory: Option<String>,
+    /// Whether the context-size note has been written since the budget was last crossed.
+    context_warned: bool,
+    /// Footnote each reply with its latency and token count, set with `/timing`.
+    timing: bool,
+    /// Write replies into the file as they arrive, set with `/stream`.
+    stream: bool,
+    /// Offer the web-search tool on the next turn, set with `/search`.
+    search: bool,
+    /// The text last snapshotted to the draft file.
+    saved_draft: Option<String>,
+    /// The file up to the open turn and that turn's role, as of the last draft update.
+    open_turn: Option<(String, Role)>,
+    /// The conversation ID of the file it was computed for.
+    conversation_id: Option<(PathBuf, String)>,
+    /// The altered turn last warned about, so each alteration is logged once.
+    altered_turn: Option<usize>,
+}
+
+impl ChatContext {
+    fn new(
+        _content: String,
+        path: PathBuf,
+        instructions: Option<String>,
+        plain_text: bool,
+        memory_file: PathBuf,
+        stream: bool,
+    ) -> Self {
+        Self {
+            path,
+            max_messages: MAX_CONTEXT_MESSAGES,
+            instructions,
+            plain_text,
+            stream,
+            redo_stack: Vec::new(),
+            language: None,
+            memory_file,
+            memory: None,
+            context_warned: false,
+            timing: false,
+            search: false,
+            saved_draft: None,
+            open_turn: None,
+            conversation_id: None,
+            altered_turn: None,
+        }
+    }
+
+    /// Snapshots the turn being typed in `content` to the draft file, or removes the draft
+    /// once that turn has been emptied.
+    async fn update_draft(&mut self, content: &str, reply_file: bool) {
+        let (start, role) = open_turn(content, self.open_turn.as_ref());
+        let unchanged = |(before, _): &(String, Role)| before.len() == start && content.starts_with(before.as_str());
+        if !self.open_turn.as_ref().is_some_and(unchanged) {
+            self.open_turn = Some((content[..start].to_string(), role));
+        }
+        match open_turn_text(&content[start..], role, reply_file) {
+            Some(text) => self.save_draft(&text).await,
+            None if self.saved_draft.is_some() => {
+                let chat_path = self.path.clone();
+                self.clear_draft(&chat_path).await;
+            }
+            None => {}
+        }
+    }
+
+    /// Snapshots `text`, the turn being typed, to the draft file unless it is there already.
+    async fn save_draft(&mut self, text: &str) {
+        if self.saved_draft.as_deref() == Some(text) {
+            return;
+        }
+        match fs::write(draft_path(&self.path), text).await {
+            Ok(()) => self.saved_draft = Some(text.to_string()),
+            Err(e) => debug_log(&format!("error: could not save draft: {}", e)),
+        }
+    }
+
+    /// Drops the draft file of `chat_path` once its turn has been sent or run as a command.
+    async fn clear_draft(&mut self, chat_path: &Path) {
+        self.saved_draft = None;
+        if let Err(e) = fs::remove_file(draft_path(chat_path)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                debug_log(&format!("error: could not remove draft: {}", e));
+            }
+        }
+    }
+
+    /// The current file's conversation ID, looked up once per file.
+    async fn conversation_id(&mut self) -> String {
+        match &self.conversation_id {
+            Some((path, id)) if *path == self.path => id.clone(),
+            _ => {
+                let id = conversation_id(&self.path).await;
+                debug_log(&format!("init: conversation ID {} for {}", id, self.path.display()));
+                self.conversation_id = Some((self.path.clone(), id.clone()));
+                id
+            }
+        }
+    }
+
+    fn memory_path(&self) -> PathBuf {
+        chat_dir(&self.path).join(&self.memory_file)
+    }
+
+    /// Re-reads the memory file so edits to it apply from the next turn.
+    async fn load_memory(&mut self) {
+        self.memory = fs::read_to_string(self.memory_path())
+            .await
+            .ok()
+            .map(|memory| memory.trim().to_string())
+            .filter(|memory| !memory.is_empty());
+    }
+
+    fn parse_messages(&self, content: &str, config: &Config) -> Vec<Message> {
+        let mut system = None;
+        let messages: Vec<(bool, Message)> = split_turns(content)
+            .into_iter()
+            .filter_map(|turn| {
+                if turn.role == Role::System {
+                    system = system_text(turn.text).map(|text| Message::new(Role::System, text));
+                    return None;
+                }
+                let part = message_text(turn.text, config.wrapper(turn.role));
+                // Under CHAT_REPLY_FILE a command stays in its turn; it was run, never sent
+                let command = turn.role == Role::User && Command::parse(&part).is_some();
+                (!part.is_empty() && !command).then(|| (is_pinned(turn.text), Message::new(turn.role, part)))
+            })
+            .collect();
+
+        // The system block and pinned turns are never trimmed away with older turns; the
+        // unpinned ones in between go first
+        let pinned = messages.iter().filter(|(pinned, _)| *pinned).count();
+        let mut unpinned = messages.len() - pinned;
+        let keep = self.max_messages.saturating_sub(pinned);
+        let kept = messages.into_iter().filter_map(|(pinned, message)| {
+            if !pinned {
+                unpinned -= 1;
+            }
+            (pinned || unpinned < keep).then_some(message)
+        });
+        system.into_iter().chain(kept).collect()
+    }
+
+    /// Prepends the configured instructions, using `role` (system or developer).
+    fn assemble(&self, history: Vec<Message>, role: Role) -> Vec<Message> {
+        let mut messages = Vec::with_capacity(history.len() + 1);
+        if let Some(instructions) = &self.instructions {
+            messages.push(Message::new(role, instructions.clone()));
+        }
+        if let Some(memory) = &self.memory {
+            messages.push(Message::new(
+                role,
+                format!("Facts the user asked you to remember:\n{}", memory),
+            ));
+        }
+        if let Some(language) = &self.language {
+            messages.push(Message::new(
+                role,
+                format!("Whatever language the user writes in, reply in {}.", language),
+            ));
+        }
+        messages.extend(history.into_iter().map(|message| match message.role {
+            Role::User => self.user_message(message.content),
+            Role::System => Message::new(role, message.content),
+            _ => message,
+        }));
+        messages
+    }
+
+    /// Builds an outgoing user message; the file itself always keeps the original markdown.
+    fn user_message(&self, content: String) -> Message {
+        let content = if self.plain_text {
+            markdown_to_plain(&content)
+        } else {
+            content
+        };
+        Message::new(Role::User, content)
+    }
+
+    fn is_last_message_from_ai(&self, content: &str, cursor_pos: usize) -> bool {
+        // Split content up to cursor into turns
+        let turns = split_turns(&content[..cursor_pos]);
+
+        match turns.last() {
+            // If there's no content after the last separator up to cursor, it was an AI
+            // message (because AI messages end with the separator)
+            Some(last) if turns.len() > 1 => strip_notes(last.text).trim().is_empty(),
+            // If no separator found before cursor, it's a user message
+            _ => false,
+        }
+    }
+
+    fn extract_new_message(&self, content: &str, cursor_pos: usize, config: &Config) -> String {
+        let turns = split_turns(&content[..cursor_pos]);
+        let cleaned = |turn: &Turn| message_text(turn.text, config.wrapper(turn.role));
+
+        // Get everything after the last separator up to cursor
+        let message = turns.last().map(cleaned).unwrap_or_default();
+        if !message.is_empty() || turns.len() < 2 {
+            return message;
+        }
+
+        // If empty after last separator, try to get the content before it
+        // (handles case where user is typing right after an AI message)
+        cleaned(&turns[turns.len() - 2])
+    }
+
+    /// Messages before the turn being sent, i.e. everything up to the last separator.
+    fn history(&self, content: &str, cursor_pos: usize, config: &Config) -> Vec<Message> {
+        let turns = split_turns(&content[..cursor_pos]);
+        match turns.last() {
+            Some(last) if turns.len() > 1 => self.parse_messages(&content[..last.start], config),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A chat file's conversation ID: a hash of its absolute path and creation time, kept in a
+/// `.<chat>.id` file beside it. Atomic saves give the file a new creation time on every
+/// write, so the ID is derived once and read back from then on.
+async fn conversation_id(chat_path: &Path) -> String {
+    use sha2::Digest;
+
+    let stored = chat_dir(chat_path).join(format!(".{}.id", file_name(chat_path)));
+    if let Ok(id) = fs::read_to_string(&stored).await {
+        if !id.trim().is_empty() {
+            return id.trim().to_string();
+        }
+    }
+
+    let path = fs::canonicalize(chat_path).await.unwrap_or_else(|_| chat_path.to_path_buf());
+    let created = fs::metadata(&path)
+        .await
+        .and_then(|metadata| metadata.created())
+        .ok()
+        .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |created| created.as_nanos());
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(path.to_string_lossy().as_bytes());
+    hasher.update(created.to_le_bytes());
+    let id: String = hasher.finalize()[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    if let Err(e) = fs::write(&stored, &id).await {
+        debug_log(&format!("error: could not store conversation ID: {}", e));
+    }
+    id
+}
+
+/// Round-robins between API keys, skipping any that are cooling down after a 429.
+struct KeyPool {
+    keys: RwLock<Vec<SecretString>>,
+    cooldowns: RwLock<Vec<Option<Instant>>>,
+    current: AtomicUsize,
+}
+
+impl KeyPool {
+    fn new(keys: Vec<SecretString>) -> Self {
+        Self {
+            cooldowns: RwLock::new(vec![None; keys.len()]),
+            keys: RwLock::new(keys),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    /// Returns the index and value of the first usable key, starting from the current one.
+    fn next_available(&self) -> Option<(usize, SecretString)> {
+        let keys = self.keys.read().unwrap();
+        let cooldowns = self.cooldowns.read().unwrap();
+        let start = self.current.load(Ordering::SeqCst);
+        (0..keys.len())
+            .map(|offset| (start + offset) % keys.len())
+            .find(|&i| cooldowns[i].is_none_or(|until| Instant::now() >= until))
+            .map(|i| {
+                self.current.store(i, Ordering::SeqCst);
+                (i, keys[i].clone())
+            })
+    }
+
+    fn cool_down(&self, index: usize, duration: Duration) {
+        self.cooldowns.write().unwrap()[index] = Some(Instant::now() + duration);
+        self.current.store((index + 1) % self.len(), Ordering::SeqCst);
+    }
+
+    /// Whether `keys` are the ones already in use, in the same order.
+    fn matches(&self, keys: &[SecretString]) -> bool {
+        let current = self.keys.read().unwrap();
+        current.len() == keys.len()
+            && current.iter().zip(keys).all(|(a, b)| a.expose_secret() == b.expose_secret())
+    }
+
+    /// Swaps in a new set of keys with no cooldowns.
+    fn replace(&self, keys: Vec<SecretString>) {
+        *self.cooldowns.write().unwrap() = vec![None; keys.len()];
+        *self.keys.write().unwrap() = keys;
+        self.current.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Counts failed and retried API calls over a rolling window; once `limit` is reached,
+/// sending stays paused until `/resume`, so an outage can't run up calls unattended.
+struct ErrorBudget {
+    limit: usize,
+    window: Duration,
+    failures: std::sync::Mutex<std::collections::VecDeque<Instant>>,
+    spent: AtomicBool,
+}
+
+impl ErrorBudget {
+    fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            failures: std::sync::Mutex::new(std::collections::VecDeque::new()),
+            spent: AtomicBool::new(false),
+        }
+    }
+
+    fn record_failure(&self) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        failures.push_back(now);
+        while failures.front().is_some_and(|&failed| now.duration_since(failed) > self.window) {
+            failures.pop_front();
+        }
+        if failures.len() >= self.limit && !self.spent.swap(true, Ordering::SeqCst) {
+            debug_log(&format!(
+                "error: {} failed or retried API calls in the last {}s; pausing until /resume",
+                failures.len(),
+                self.window.as_secs()
+            ));
+        }
+    }
+
+    fn is_spent(&self) -> bool {
+        self.spent.load(Ordering::SeqCst)
+    }
+
+    /// Clears the pause and the failures that led to it; returns whether it was paused.
+    fn resume(&self) -> bool {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        self.spent.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl std::str::FromStr for ReasoningEffort {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            other => anyhow::bail!("Reasoning effort must be low, medium or high, got {:?}", other),
+        }
+    }
+}
+
+impl ReasoningEffort {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+}
+
+/// What `CHAT_COPY_REPLY` puts on the clipboard after each turn.
+#[derive(Debug, Clone, Copy)]
+enum CopyReply {
+    Reply,
+    FirstCode,
+    LastCode,
+}
+
+impl std::str::FromStr for CopyReply {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "1" | "true" | "reply" => Ok(Self::Reply),
+            "first-code" => Ok(Self::FirstCode),
+            "last-code" => Ok(Self::LastCode),
+            other => anyhow::bail!("CHAT_COPY_REPLY must be 1, first-code or last-code, got {:?}", other),
+        }
+    }
+}
+
+impl CopyReply {
+    fn select(self, reply: &str) -> Option<String> {
+        match self {
+            Self::Reply => Some(reply.trim().to_string()),
+            Self::FirstCode => code_blocks(reply).into_iter().next(),
+            Self::LastCode => code_blocks(reply).pop(),
+        }
+    }
+}
+
+/// What `CHAT_REPLY_ENDING` writes after a reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplyEnding {
+    /// A separator, so the next user turn is already open.
+    Separator,
+    /// A blank line; the separator goes in once the user writes below the reply.
+    BlankLine,
+    /// Just the newline ending the reply's last line, likewise.
+    Nothing,
+}
+
+impl std::str::FromStr for ReplyEnding {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "separator" => Ok(Self::Separator),
+            "blank" => Ok(Self::BlankLine),
+            "none" => Ok(Self::Nothing),
+            other => anyhow::bail!("CHAT_REPLY_ENDING must be separator, blank or none, got {:?}", other),
+        }
+    }
+}
+
+impl ReplyEnding {
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Separator => separator(),
+            Self::BlankLine => DOUBLE_NEWLINE,
+            Self::Nothing => "\n",
+        }
+    }
+}
+
+/// How a role's turns are written into the file. Parsing takes it off again, so what is
+/// sent is the same as with plain turns.
+#[derive(Debug, Clone, PartialEq)]
+enum Wrapper {
+    /// Every line quoted with `> `.
+    Blockquote,
+    /// Text around the content, from a template like `<div class="user">{}</div>`.
+    Template { before: String, after: String },
+}
+
+/// Reads a wrapper setting: `plain` (no wrapper), `blockquote`, or a template with one `{}`
+/// where the content goes. `\n` in a template stands for a newline.
+fn parse_wrapper(name: &str, value: &str) -> Result<Option<Wrapper>> {
+    match value {
+        "plain" => Ok(None),
+        "blockquote" => Ok(Some(Wrapper::Blockquote)),
+        template => {
+            let template = template.replace("\\n", "\n");
+            let (before, after) = template
+                .split_once("{}")
+                .filter(|(before, after)| {
+                    !after.contains("{}") && (!before.trim().is_empty() || !after.trim().is_empty())
+                })
+                .with_context(|| {
+                    format!("{} must be plain, blockquote or a template with one {{}}, got {:?}", name, value)
+                })?;
+            Ok(Some(Wrapper::Template { before: before.to_string(), after: after.to_string() }))
+        }
+    }
+}
+
+impl Wrapper {
+    fn wrap(&self, text: &str) -> String {
+        let text = text.trim();
+        match self {
+            Self::Blockquote => text
+                .lines()
+                .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Template { before, after } => format!("{}{}{}", before, text, after),
+        }
+    }
+
+    /// The content of a turn written with this wrapper; `None` when `text` isn't wrapped.
+    fn unwrap(&self, text: &str) -> Option<String> {
+        let text = text.trim();
+        match self {
+            Self::Blockquote => text
+                .lines()
+                .map(|line| line.strip_prefix("> ").or_else(|| line.strip_prefix('>')))
+                .collect::<Option<Vec<_>>>()
+                .map(|lines| lines.join("\n").trim().to_string()),
+            Self::Template { before, after } => text
+                .strip_prefix(before.trim_start())
+                .and_then(|rest| rest.strip_suffix(after.trim_end()))
+                .map(|inner| inner.trim().to_string()),
+        }
+    }
+}
+
+/// `text` written with `wrapper`, unless it already is.
+fn wrap_turn(wrapper: Option<&Wrapper>, text: &str) -> String {
+    match wrapper {
+        Some(wrapper) if wrapper.unwrap(text).is_none() => wrapper.wrap(text),
+        _ => text.to_string(),
+    }
+}
+
+/// Writes the open turn with the user wrapper, leaving everything before it as it is.
+fn wrap_last_turn(content: &str, wrapper: Option<&Wrapper>) -> String {
+    let turns = split_turns(content);
+    match turns.last() {
+        Some(last) if last.role == Role::User && wrapper.is_some() && !last.text.trim().is_empty() => {
+            format!("{}{}\n", &content[..last.start], wrap_turn(wrapper, last.text))
+        }
+        _ => content.to_string(),
+    }
+}
+
+/// `reply` with the marker `split_after_reply` looks for, unless a separator will follow it.
+fn with_reply_end(reply: &str, ending: ReplyEnding) -> String {
+    match ending {
+        ReplyEnding::Separator => reply.to_string(),
+        _ => format!("{}\n\n{}", reply.trim_end(), REPLY_END),
+    }
+}
+
+/// With a reply written without a trailing separator, text the user has since typed below
+/// it is still part of the assistant turn. Puts the separator in place of the marker written
+/// after the reply, which keeps working after a restart or an edit to the reply; `None` when
+/// there is nothing after the marker, or no marker.
+fn split_after_reply(content: &str) -> Option<String> {
+    let last = split_turns(content).pop().filter(|turn| turn.role == Role::Assistant)?;
+    let marker = last.start + last.text.rfind(REPLY_END)?;
+    let rest = &content[marker + REPLY_END.len()..];
+    if rest.trim().is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}{}{}",
+        content[..marker].trim_end(),
+        separator(),
+        rest.trim_start_matches([' ', '\t', '\n'])
+    ))
+}
+
+/// What `CHAT_VERIFY_WRITES` does when a written file no longer parses as expected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VerifyWrites {
+    Warn,
+    Repair,
+}
+
+impl std::str::FromStr for VerifyWrites {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "1" | "true" | "warn" => Ok(Self::Warn),
+            "repair" => Ok(Self::Repair),
+            other => anyhow::bail!("CHAT_VERIFY_WRITES must be warn or repair, got {:?}", other),
+        }
+    }
+}
+
+/// Checks that a file just written with a reply still parses as intended: the reply is the
+/// last turn and is an assistant turn, and no empty turn (a doubled separator) has shifted
+/// the alternation.
+async fn verify_written(path: &Path, mode: VerifyWrites, newest_first: bool) -> Result<()> {
+    let written = read_chat_file(path, newest_first).await?;
+    let problems = turn_problems(&written);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        debug_log(&format!("error: post-write check of {}: {}", file_name(path), problem));
+    }
+
+    if mode == VerifyWrites::Repair {
+        let repaired = collapse_separators(&written).map(|(collapsed, _)| collapsed);
+        if let Some(repaired) = repaired.filter(|repaired| turn_problems(repaired).is_empty()) {
+            write_chat_file(path, &repaired, newest_first).await?;
+            debug_log("write: repaired separators after post-write check");
+        } else {
+            debug_log("error: dropping empty turns did not fix the file; left as written");
+        }
+    }
+    Ok(())
+}
+
+fn turn_problems(content: &str) -> Vec<String> {
+    let turns = split_turns(content);
+    let mut problems = Vec::new();
+    let Some((last, earlier)) = turns.split_last() else {
+        return problems;
+    };
+
+    if !last.text.trim().is_empty() {
+        problems.push("file does not end with a separator after the reply".to_string());
+    }
+    match earlier.last() {
+        Some(reply) if reply.role == Role::Assistant && !reply.text.trim().is_empty() => {}
+        Some(reply) => problems.push(format!("last reply parses as a {:?} turn", reply.role)),
+        None => problems.push("no reply found".to_string()),
+    }
+    for (i, turn) in earlier.iter().enumerate() {
+        if turn.role != Role::System && turn.text.trim().is_empty() {
+            problems.push(format!("turn {} is empty (doubled separator?)", i + 1));
+        }
+    }
+    problems
+}
+
+/// Kept open for the whole run: on X11 the copied text disappears once its owner is dropped.
+static CLIPBOARD: std::sync::Mutex<Option<arboard::Clipboard>> = std::sync::Mutex::new(None);
+
+fn copy_to_clipboard(text: String) -> Result<()> {
+    let mut clipboard = CLIPBOARD.lock().unwrap_or_else(|e| e.into_inner());
+    if clipboard.is_none() {
+        *clipboard = Some(arboard::Clipboard::new()?);
+    }
+    clipboard
+        .as_mut()
+        .expect("clipboard was just opened")
+        .set_text(text)?;
+    Ok(())
+}
+
+/// The model to call and what it accepts.
+#[derive(Debug, Clone)]
+struct Provider {
+    model: String,
+    supports_developer_role: bool,
+    reasoning_effort: Option<ReasoningEffort>,
+    /// Request field the provider reads the reasoning effort from.
+    reasoning_field: String,
+    /// Offer the allow-listed `shell` tool (`--allow-tools` plus `CHAT_TOOL_ALLOWLIST`).
+    shell_tool: bool,
+    /// The provider's web-search tool from `CHAT_SEARCH_TOOL`, offered on turns after `/search`.
+    search_tool: Option<serde_json::Value>,
+    /// Sent as the request's `user` field.
+    user: Option<String>,
+    /// User-supplied request fields from `DEEPSEEK_EXTRA_BODY`, applied last.
+    extra_body: serde_json::Map<String, serde_json::Value>,
+    /// Patterns replaced with `[REDACTED]` in everything sent; chat.md is left alone.
+    redactions: Vec<regex::Regex>,
+    /// Commands that map the request body into the provider's shape and its response back,
+    /// JSON in on stdin and out on stdout.
+    request_transform: Option<String>,
+    response_transform: Option<String>,
+}
+
+impl Provider {
+    fn is_reasoning_model(model: &str) -> bool {
+        model.contains("reasoner") || ["o1", "o3", "o4"].iter().any(|prefix| model.starts_with(prefix))
+    }
+
+    fn redact(&self, messages: &mut [Message]) {
+        let mut count = 0;
+        for message in messages.iter_mut() {
+            for pattern in &self.redactions {
+                let matches = pattern.find_iter(&message.content).count();
+                if matches > 0 {
+                    count += matches;
+                    message.content = pattern.replace_all(&message.content, "[REDACTED]").into_owned();
+                }
+            }
+        }
+        if count > 0 {
+            debug_log(&format!("trim: redacted {} match(es) before sending", count));
+        }
+    }
+
+    /// Builds the request body for `model`, adding the parameters it supports. `search`
+    /// offers the web-search tool alongside any others.
+    fn request(&self, model: &str, mut messages: Vec<Message>, tools: bool, search: bool) -> ApiRequest {
+        self.redact(&mut messages);
+
+        let mut extra = serde_json::Map::new();
+        if let Some(effort) = self.reasoning_effort.filter(|_| Self::is_reasoning_model(model)) {
+            extra.insert(self.reasoning_field.clone(), effort.as_str().into());
+        }
+        let mut offered = Vec::new();
+        if tools && self.shell_tool {
+            offered.push(shell_tool_definition());
+        }
+        if let Some(tool) = self.search_tool.as_ref().filter(|_| search) {
+            offered.push(tool.clone());
+        }
+        if !offered.is_empty() {
+            extra.insert("tools".to_string(), offered.into());
+        }
+        extra.extend(self.extra_body.clone());
+
+        ApiRequest {
+            model: model.to_string(),
+            messages,
+            user: self.user.clone(),
+            extra,
+        }
+    }
+}
+
+struct ApiClient {
+    client: reqwest::Client,
+    keys: KeyPool,
+    api_url: RwLock<String>,
+    provider: Provider,
+    /// Extra attempts when a response has no choices, which is usually transient.
+    empty_retries: u32,
+    rate_limit_floor: u64,
+    /// Set when a response reported the quota nearly spent; the next request waits until then.
+    throttled_until: RwLock<Option<Instant>>,
+    error_budget: Option<ErrorBudget>,
+    /// Calls rejected for the key in a row, reset by any call that gets through.
+    auth_failures: AtomicUsize,
+    /// Header name and conversation ID sent with every request, when configured.
+    conversation: RwLock<Option<(String, String)>>,
+}
+
+impl ApiClient {
+    fn new(
+        api_keys: Vec<SecretString>,
+        api_url: String,
+        provider: Provider,
+        empty_retries: u32,
+        rate_limit_floor: u64,
+        error_budget: Option<ErrorBudget>,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("Failed to create HTTP client"),
+            keys: KeyPool::new(api_keys),
+            api_url: RwLock::new(api_url),
+            provider,
+            empty_retries,
+            rate_limit_floor,
+            throttled_until: RwLock::new(None),
+            error_budget,
+            auth_failures: AtomicUsize::new(0),
+            conversation: RwLock::new(None),
+        }
+    }
+
+    fn set_conversation(&self, header: &str, id: String) {
+        *self.conversation.write().unwrap() = Some((header.to_string(), id));
+    }
+
+    fn record_failure(&self) {
+        if let Some(budget) = &self.error_budget {
+            budget.record_failure();
+        }
+    }
+
+    /// Whether too many calls have failed lately for turns to be sent.
+    fn is_paused(&self) -> bool {
+        self.error_budget.as_ref().is_some_and(ErrorBudget::is_spent)
+    }
+
+    /// Role for high-priority instructions, falling back to `system` when unsupported.
+    fn instruction_role(&self) -> Role {
+        if self.provider.supports_developer_role {
+            Role::Developer
+        } else {
+            Role::System
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        self.api_url.read().unwrap().clone()
+    }
+
+    fn set_endpoint(&self, api_url: String) {
+        *self.api_url.write().unwrap() = api_url;
+    }
+
+    /// Sends one request, retrying on a dropped connection. The pool discards the dead
+    /// connection, so the retry transparently reconnects.
+    async fn send(&self, api_key: &SecretString, request: &serde_json::Value) -> Result<reqwest::Response, ChatError> {
+        let mut attempt = 0;
+        loop {
+            let mut builder = self
+                .client
+                .post(self.endpoint())
+                .bearer_auth(api_key.expose_secret())
+                .header("Content-Type", "application/json")
+                .json(request);
+            // A stream can outlast the client's timeout; stalls are caught per chunk instead
+            if request["stream"] == true {
+                builder = builder.timeout(STREAM_TIMEOUT);
+            }
+            if let Some((header, id)) = &*self.conversation.read().unwrap() {
+                builder = builder.header(header.as_str(), id.as_str());
+            }
+            let result = builder.send().await;
+
+            match result {
+                Err(e) if attempt < MAX_CONNECTION_RETRIES && is_connection_error(&e) => {
+                    attempt += 1;
+                    self.record_failure();
+                    debug_log(&format!("skip: connection dropped ({}), reconnecting", e));
+                    tokio::time::sleep(CONNECTION_RETRY_DELAY * attempt).await;
+                }
+                result => return result.map_err(ChatError::Network),
+            }
+        }
+    }
+
+    /// `search` offers the web-search tool as well, on the turn after `/search`.
+    async fn call_api(&self, messages: Vec<Message>, search: bool) -> Result<Completion, ChatError> {
+        let started = Instant::now();
+        let result = self.request_completion(&self.provider.model, messages, true, search).await;
+        self.record_outcome(started, &result);
+        result
+    }
+
+    /// `tools` offers the shell tool, when enabled; side requests like titles leave it out.
+    async fn call_model(&self, model: &str, messages: Vec<Message>, tools: bool) -> Result<Completion, ChatError> {
+        let started = Instant::now();
+        let result = self.request_completion(model, messages, tools, false).await;
+        self.record_outcome(started, &result);
+        result
+    }
+
+    /// Like `call_api` without tools, but streamed: `partial` holds the reply text so far.
+    async fn stream_api(
+        &self,
+        messages: Vec<Message>,
+        partial: &tokio::sync::watch::Sender<String>,
+    ) -> Result<Completion, ChatError> {
+        let started = Instant::now();
+        let result = self.request_stream(messages, partial).await;
+        self.record_outcome(started, &result);
+        result
+    }
+
+    /// Whether replies can be streamed; tool calls and response transforms need the whole
+    /// response at once.
+    fn can_stream(&self) -> bool {
+        !self.provider.shell_tool && self.provider.response_transform.is_none()
+    }
+
+    fn record_outcome(&self, started: Instant, result: &Result<Completion, ChatError>) {
+        METRICS.record_request(started.elapsed(), result);
+        if result.is_err() {
+            self.record_failure();
+        }
+        match result {
+            Err(ChatError::Auth(_)) => self.auth_failures.fetch_add(1, Ordering::Relaxed),
+            _ => self.auth_failures.swap(0, Ordering::Relaxed),
+        };
+    }
+
+    async fn request_stream(
+        &self,
+        messages: Vec<Message>,
+        partial: &tokio::sync::watch::Sender<String>,
+    ) -> Result<Completion, ChatError> {
+        let model = &self.provider.model;
+        let mut request =
+            serde_json::to_value(self.provider.request(model, messages, false, false)).expect("request serializes");
+        request["stream"] = true.into();
+        request["stream_options"] = serde_json::json!({ "include_usage": true });
+        let request = match &self.provider.request_transform {
+            Some(command) => run_transform(command, request).await.map_err(ChatError::Transform)?,
+            None => request,
+        };
+
+        // Retried like an empty `choices` array, as long as nothing has been streamed yet
+        for attempt in 1..=self.empty_retries + 1 {
+            let completion = self.stream_once(&request, partial).await?;
+            if !completion.content.is_empty() {
+                return Ok(completion);
+            }
+            if attempt <= self.empty_retries {
+                self.record_failure();
+                debug_log(&format!(
+                    "skip: API streamed an empty reply, retrying ({}/{})",
+                    attempt, self.empty_retries
+                ));
+                tokio::time::sleep(CONNECTION_RETRY_DELAY * attempt).await;
+            }
+        }
+        Err(ChatError::NoChoices {
+            attempts: self.empty_retries + 1,
+        })
+    }
+
+    /// One streamed request; the completion's content is empty if the reply was.
+    async fn stream_once(
+        &self,
+        request: &serde_json::Value,
+        partial: &tokio::sync::watch::Sender<String>,
+    ) -> Result<Completion, ChatError> {
+        let model = &self.provider.model;
+        let mut response = self.send_with_keys(request).await?;
+        if let Some(error) = status_error(response.status()) {
+            return Err(error);
+        }
+
+        let mut completion = Completion {
+            content: String::new(),
+            tool_calls: Vec::new(),
+            usage: None,
+            model: model.clone(),
+            citations: Vec::new(),
+        };
+        let mut buffer = Vec::new();
+        'stream: loop {
+            let chunk = tokio::time::timeout(STREAM_IDLE_TIMEOUT, response.chunk())
+                .await
+                .map_err(|_| ChatError::Stream(format!("no data for {}s", STREAM_IDLE_TIMEOUT.as_secs())))?
+                .map_err(ChatError::Network)?;
+            let Some(chunk) = chunk else { break };
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    break 'stream;
+                }
+                let event: StreamEvent =
+                    serde_json::from_str(data).map_err(|e| ChatError::Stream(format!("bad event: {}", e)))?;
+                if let Some(model) = event.model {
+                    completion.model = model;
+                }
+                if event.usage.is_some() {
+                    completion.usage = event.usage;
+                }
+                for text in event.choices.into_iter().filter_map(|choice| choice.delta.content) {
+                    completion.content.push_str(&text);
+                }
+                partial.send_replace(completion.content.clone());
+            }
+        }
+        Ok(completion)
+    }
+
+    /// Asks for a completion, with the web-search tool when `search` is set. A provider that
+    /// turns the tool down as a bad request is asked again without it.
+    async fn request_completion(
+        &self,
+        model: &str,
+        messages: Vec<Message>,
+        tools: bool,
+        search: bool,
+    ) -> Result<Completion, ChatError> {
+        if !tools || !search {
+            return self.attempt_completion(model, messages, tools, false).await;
+        }
+        match self.attempt_completion(model, messages.clone(), tools, true).await {
+            Err(ChatError::Status(
+                status @ (reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNPROCESSABLE_ENTITY),
+            )) => {
+                debug_log(&format!("skip: provider rejected the web-search tool ({}), asking without it", status));
+                self.attempt_completion(model, messages, tools, false).await
+            }
+            result => result,
+        }
+    }
+
+    async fn attempt_completion(
+        &self,
+        model: &str,
+        messages: Vec<Message>,
+        tools: bool,
+        search: bool,
+    ) -> Result<Completion, ChatError> {
+        let request = self.provider.request(model, messages, tools, search);
+        let request = serde_json::to_value(&request).expect("request serializes");
+        let request = match &self.provider.request_transform {
+            Some(command) => run_transform(command, request).await.map_err(ChatError::Transform)?,
+            None => request,
+        };
+
+        for attempt in 1..=self.empty_retries + 1 {
+            let response = self.send_with_keys(&request).await?;
+            if let Some(error) = status_error(response.status()) {
+                return Err(error);
+            }
+
+            let api_resp: ApiResponse = match &self.provider.response_transform {
+                Some(command) => {
+                    let raw = response.json().await.map_err(ChatError::Parse)?;
+                    let mapped = run_transform(command, raw).await.map_err(ChatError::Transform)?;
+                    serde_json::from_value(mapped).map_err(|e| ChatError::Transform(e.into()))?
+                }
+                None => response.json().await.map_err(ChatError::Parse)?,
+            };
+            if let Some(choice) = api_resp.choices.into_iter().next() {
+                let annotated = choice.message.annotations.into_iter().filter_map(|annotation| annotation.url_citation);
+                let listed = api_resp.citations.into_iter().map(|url| Citation { url, title: None });
+                let mut citations: Vec<Citation> = Vec::new();
+                for citation in listed.chain(annotated) {
+                    if !citations.iter().any(|seen| seen.url == citation.url) {
+                        citations.push(citation);
+                    }
+                }
+                return Ok(Completion {
+                    content: choice.message.message.content,
+                    tool_calls: choice.message.message.tool_calls,
+                    usage: api_resp.usage,
+                    model: api_resp.model.unwrap_or_else(|| model.to_string()),
+                    citations,
+                });
+            }
+            if attempt <= self.empty_retries {
+                self.record_failure();
+                debug_log(&format!(
+                    "skip: API returned an empty choices array, retrying ({}/{})",
+                    attempt, self.empty_retries
+                ));
+                tokio::time::sleep(CONNECTION_RETRY_DELAY * attempt).await;
+            }
+        }
+        Err(ChatError::NoChoices {
+            attempts: self.empty_retries + 1,
+        })
+    }
+
+    /// Waits out a throttle set by `note_rate_limit`, if one is still running.
+    async fn wait_for_quota(&self) {
+        let until = self.throttled_until.write().unwrap().take();
+        if let Some(wait) = until.and_then(|until| until.checked_duration_since(Instant::now())) {
+            debug_log(&format!("skip: rate limit nearly reached, waiting {:.1}s", wait.as_secs_f64()));
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Reads `x-ratelimit-remaining`/`x-ratelimit-reset` (or their `-requests` variants) and,
+    /// when no more than `rate_limit_floor` requests are left, holds the next request until
+    /// the reset so it doesn't run into a 429.
+    fn note_rate_limit(&self, headers: &reqwest::header::HeaderMap) {
+        let header = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name))
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+        };
+        let Some(remaining) = header(&["x-ratelimit-remaining-requests", "x-ratelimit-remaining"])
+            .and_then(|value| value.parse::<u64>().ok())
+        else {
+            return;
+        };
+        let reset = header(&["x-ratelimit-reset-requests", "x-ratelimit-reset"]).and_then(parse_rate_limit_reset);
+        match reset {
+            Some(reset) => debug_log(&format!(
+                "response: {} requests remaining, quota resets in {:.1}s",
+                remaining,
+                reset.as_secs_f64()
+            )),
+            None => debug_log(&format!("response: {} requests remaining", remaining)),
+        }
+
+        if remaining <= self.rate_limit_floor {
+            let wait = reset.unwrap_or(KEY_COOLDOWN).min(MAX_RATE_LIMIT_WAIT);
+            *self.throttled_until.write().unwrap() = Some(Instant::now() + wait);
+        }
+    }
+
+    /// Sends `request`, moving on to the next API key whenever one is rate limited.
+    async fn send_with_keys(&self, request: &serde_json::Value) -> Result<reqwest::Response, ChatError> {
+        self.wait_for_quota().await;
+        loop {
+            let (index, api_key) = self.keys.next_available().ok_or(ChatError::RateLimited)?;
+            if self.keys.len() > 1 {
+                debug_log(&format!("call: using API key #{}", index + 1));
+            }
+
+            let response = self.send(&api_key, request).await?;
+
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                if self.rate_limit_floor > 0 {
+                    self.note_rate_limit(response.headers());
+                }
+                return Ok(response);
+            }
+
+            let cooldown = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(KEY_COOLDOWN);
+            debug_log(&format!(
+                "skip: API key #{} rate limited, cooling down for {}s",
+                index + 1,
+                cooldown.as_secs()
+            ));
+            self.keys.cool_down(index, cooldown);
+        }
+    }
+}
+
+/// The error for an unsuccessful response status, `None` on success.
+fn status_error(status: reqwest::StatusCode) -> Option<ChatError> {
+    match status {
+        status if status.is_success() => None,
+        status @ (reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN) => Some(ChatError::Auth(status)),
+        status => Some(ChatError::Status(status)),
+    }
+}
+
+/// A rate-limit reset as providers send it: seconds (`30`, `0.5`), a Unix timestamp, or a Go
+/// style duration such as `1m30s` or `250ms`.
+fn parse_rate_limit_reset(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.parse::<f64>() {
+        if !secs.is_finite() || secs < 0.0 {
+            return None;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+        // Anything past the year 2001 is a timestamp, not a wait
+        return Some(Duration::from_secs_f64(if secs > 1e9 { (secs - now).max(0.0) } else { secs }));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let amount: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += Duration::from_secs_f64(amount * scale);
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+/// Where `/share` uploads the conversation.
+#[derive(Debug)]
+enum ShareTarget {
+    /// A paste service that takes the raw text as the POST body and answers with its URL.
+    Paste { url: String, token: Option<String> },
+    /// A secret GitHub gist.
+    Gist { token: String },
+}
+
+/// Uploads `content` and returns the link to it.
+async fn share(client: &reqwest::Client, target: &ShareTarget, name: &str, content: &str) -> Result<String> {
+    let request = match target {
+        ShareTarget::Paste { url, token } => {
+            let request = client.post(url).body(content.to_string());
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+        }
+        ShareTarget::Gist { token } => client
+            .post(GIST_API_URL)
+            .bearer_auth(token)
+            .header(reqwest::header::USER_AGENT, env!("CARGO_PKG_NAME"))
+            .json(&serde_json::json!({
+                "description": name,
+                "public": false,
+                "files": { name: { "content": content } },
+            })),
+    };
+
+    let response = request.send().await.context("Share upload failed")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Share upload failed: status {}", response.status());
+    }
+    let url = match target {
+        ShareTarget::Paste { .. } => response.text().await?.trim().to_string(),
+        ShareTarget::Gist { .. } => response.json::<serde_json::Value>().await?["html_url"]
+            .as_str()
+            .context("Gist response has no html_url")?
+            .to_string(),
+    };
+    if url.is_empty() {
+        anyhow::bail!("Share service returned no URL");
+    }
+    Ok(url)
+}
+
+/// Downloads a shared conversation: the raw body of `url`, or for a gist link (as `/share`
+/// makes), the content of its first file through the GitHub API.
+async fn fetch_conversation(client: &reqwest::Client, url: &str) -> Result<String> {
+    let parsed = reqwest::Url::parse(url)?;
+    let gist_id = (parsed.host_str() == Some("gist.github.com"))
+        .then(|| parsed.path_segments()?.next_back().filter(|id| !id.is_empty()))
+        .flatten();
+    let request = match gist_id {
+        Some(id) => client
+            .get(format!("{}/{}", GIST_API_URL, id))
+            .header(reqwest::header::USER_AGENT, env!("CARGO_PKG_NAME")),
+        None => client.get(url),
+    };
+
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to fetch conversation from {}", url))?;
+    if !response.status().is_success() {
+        anyhow::bail!("Failed to fetch conversation from {}: status {}", url, response.status());
+    }
+    if gist_id.is_none() {
+        return Ok(response.text().await?);
+    }
+    let gist: serde_json::Value = response.json().await.context("Malformed gist response")?;
+    gist["files"]
+        .as_object()
+        .and_then(|files| files.values().next())
+        .and_then(|file| file["content"].as_str())
+        .map(str::to_string)
+        .with_context(|| format!("Gist {} has no files", url))
+}
+
+/// Counters exposed with `--metrics`, in Prometheus text format.
+struct Metrics {
+    requests: AtomicU64,
+    request_errors: AtomicU64,
+    request_micros: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    turns: AtomicU64,
+    errors: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    requests: AtomicU64::new(0),
+    request_errors: AtomicU64::new(0),
+    request_micros: AtomicU64::new(0),
+    prompt_tokens: AtomicU64::new(0),
+    completion_tokens: AtomicU64::new(0),
+    turns: AtomicU64::new(0),
+    errors: AtomicU64::new(0),
+};
+
+impl Metrics {
+    fn record_request(&self, elapsed: Duration, result: &Result<Completion, ChatError>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.request_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        match result {
+            Ok(completion) => {
+                if let Some(usage) = &completion.usage {
+                    self.prompt_tokens.fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+                    self.completion_tokens.fetch_add(usage.completion_tokens, Ordering::Relaxed);
+                }
+            }
+            Err(_) => {
+                self.request_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let seconds = get(&self.request_micros) as f64 / 1_000_000.0;
+        [
+            "# HELP chat_requests_total API requests made.".to_string(),
+            "# TYPE chat_requests_total counter".to_string(),
+            format!("chat_requests_total {}", get(&self.requests)),
+            "# HELP chat_request_duration_seconds Time spent waiting for the API.".to_string(),
+            "# TYPE chat_request_duration_seconds summary".to_string(),
+            format!("chat_request_duration_seconds_sum {}", seconds),
+            format!("chat_request_duration_seconds_count {}", get(&self.requests)),
+            "# HELP chat_tokens_total Tokens reported by the API.".to_string(),
+            "# TYPE chat_tokens_total counter".to_string(),
+            format!("chat_tokens_total{{kind=\"prompt\"}} {}", get(&self.prompt_tokens)),
+            format!("chat_tokens_total{{kind=\"completion\"}} {}", get(&self.completion_tokens)),
+            "# HELP chat_turns_total Replies written to a chat file.".to_string(),
+            "# TYPE chat_turns_total counter".to_string(),
+            format!("chat_turns_total {}", get(&self.turns)),
+            "# HELP chat_errors_total Failed API requests and failed processing passes.".to_string(),
+            "# TYPE chat_errors_total counter".to_string(),
+            format!("chat_errors_total{{stage=\"request\"}} {}", get(&self.request_errors)),
+            format!("chat_errors_total{{stage=\"process\"}} {}", get(&self.errors)),
+        ]
+        .join("\n")
+            + "\n"
+    }
+}
+
+/// The comma-separated keys of `DEEPSEEK_API_KEYS` or `DEEPSEEK_API_KEY`.
+fn parse_api_keys(raw: &str) -> Vec<SecretString> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(SecretString::from)
+        .collect()
+}
+
+/// The API keys as the env files currently set them, highest precedence first, without
+/// touching the process environment.
+fn keys_from_env_files() -> Option<Vec<SecretString>> {
+    env_files().iter().rev().find_map(|file| {
+        let content = Zeroizing::new(std::fs::read_to_string(file).ok()?);
+        let raw = env_file_value(&content, "DEEPSEEK_API_KEYS").or_else(|| env_file_value(&content, "DEEPSEEK_API_KEY"))?;
+        Some(parse_api_keys(raw)).filter(|keys| !keys.is_empty())
+    })
+}
+
+/// The value of `name` in a `.env` file's `NAME=value` lines, unquoted.
+fn env_file_value<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    content.lines().find_map(|line| {
+        let line = line.trim();
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=')?;
+        if key.trim() != name {
+            return None;
+        }
+        let value = value.trim();
+        Some(
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value),
+        )
+    })
+}
+
+/// Whether the chat file and the directory holding it are both gone, e.g. a temp dir that
+/// was cleaned up. Nothing more will arrive from a watch on it.
+async fn chat_dir_gone(chat_path: &Path) -> bool {
+    fn not_found<T>(result: std::io::Result<T>) -> bool {
+        matches!(result, Err(e) if e.kind() == std::io::ErrorKind::NotFound)
+    }
+    // Resolving `.` fails once the working directory is removed, where looking it up doesn't
+    not_found(fs::metadata(chat_path).await) && not_found(fs::canonicalize(chat_dir(chat_path)).await)
+}
+
+/// Waits until `chat_path` exists again; `false` if interrupted first.
+async fn wait_for_chat_file(chat_path: &Path) -> bool {
+    while !fs::try_exists(chat_path).await.unwrap_or(false) {
+        tokio::select! {
+            _ = tokio::time::sleep(MISSING_DIR_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c() => return false,
+        }
+    }
+    true
+}
+
+/// Once the key has been rejected a few times in a row, checks the env files for a
+/// different one and switches to it, so a daemon picks up a fixed key without a restart.
+async fn reload_keys(api_client: Arc<ApiClient>) {
+    loop {
+        tokio::time::sleep(KEY_RELOAD_INTERVAL).await;
+        if api_client.auth_failures.load(Ordering::Relaxed) < KEY_RELOAD_AFTER {
+            continue;
+        }
+        let Some(keys) = keys_from_env_files() else { continue };
+        if api_client.keys.matches(&keys) {
+            continue;
+        }
+        debug_log(&format!("load: reloaded {} API key(s) from the env files", keys.len()));
+        api_client.keys.replace(keys);
+        api_client.auth_failures.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Answers every connection with the current metrics; the request itself is ignored.
+async fn serve_metrics(listener: tokio::net::TcpListener) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            let body = METRICS.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Connection-level failures (reset, broken pipe, closed pooled connection, HTTP/2 GOAWAY)
+/// that a fresh connection usually fixes.
+fn is_connection_error(error: &reqwest::Error) -> bool {
+    use std::error::Error;
+    use std::io::ErrorKind;
+
+    let mut source = error.source();
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        if let Some(hyper) = err.downcast_ref::<hyper::Error>() {
+            if hyper.is_incomplete_message() || hyper.is_closed() || hyper.is_canceled() {
+                return true;
+            }
+        }
+        if let Some(h2) = err.downcast_ref::<h2::Error>() {
+            if h2.is_go_away() || h2.is_io() {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Re-reads the chat file, reading only the bytes appended since the last read when the file
+/// just grew.
+struct ChatFileReader {
+    content: String,
+    /// An incomplete character at the end of the last read, completed by the next one.
+    pending: Vec<u8>,
+}
+
+impl ChatFileReader {
+    fn new(content: String) -> Self {
+        Self { content, pending: Vec::new() }
+    }
+
+    /// Bytes of the file read so far.
+    fn len(&self) -> usize {
+        self.content.len() + self.pending.len()
+    }
+
+    /// Reads what was appended when the file grew and its last `APPEND_CHECK_BYTES` before that
+    /// are as they were read; anything else (shrunk, same size or edited near the end, even in
+    /// a save that also appends) reads the whole file again.
+    async fn read(&mut self, path: &Path) -> Result<String> {
+        use tokio::io::AsyncSeekExt;
+
+        let mut file = fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let known = self.len();
+        if size > known as u64 {
+            // The end of what was read before, to check it is still there
+            let check_from = self.content.len().saturating_sub(APPEND_CHECK_BYTES);
+            let mut expected = self.content.as_bytes()[check_from..].to_vec();
+            expected.extend_from_slice(&self.pending);
+
+            file.seek(std::io::SeekFrom::Start(check_from as u64)).await?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).await?;
+            if bytes.starts_with(&expected) {
+                return self.append(bytes.split_off(expected.len()));
+            }
+        }
+        self.replace(fs::read(path).await?)
+    }
+
+    /// Takes bytes appended to the file since the last read, after any incomplete character
+    /// kept from it.
+    fn append(&mut self, tail: Vec<u8>) -> Result<String> {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend(tail);
+        let (text, rest) = split_utf8(bytes)?;
+        self.content.push_str(&text);
+        self.pending = rest;
+        Ok(self.content.clone())
+    }
+
+    /// Takes the whole file's current bytes.
+    fn replace(&mut self, bytes: Vec<u8>) -> Result<String> {
+        let (text, rest) = split_utf8(bytes)?;
+        self.content = text;
+        self.pending = rest;
+        Ok(self.content.clone())
+    }
+}
+
+/// Decodes `bytes` up to the last whole character, returning the text and any incomplete
+/// character at the end to prepend to the next read. Invalid UTF-8 anywhere else is an error.
+fn split_utf8(mut bytes: Vec<u8>) -> Result<(String, Vec<u8>)> {
+    let valid = match std::str::from_utf8(&bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(e) => return Err(e).context("Chat file is not valid UTF-8"),
+    };
+    let rest = bytes.split_off(valid);
+    Ok((String::from_utf8(bytes).expect("checked to be valid UTF-8"), rest))
+}
+
+/// Whether `name` is a single file name, without directories or `..`, so joining it to a
+/// directory stays inside that directory.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(components.next(), Some(std::path::Component::Normal(_))) && components.next().is_none()
+}
+
+/// Directory holding `path`, or the current directory for a bare file name.
+fn chat_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Creates a missing chat file, empty or as a copy of `template`, so there is something to
+/// watch from the start.
+async fn create_chat_file(path: &Path, template: Option<&Path>, newest_first: bool) -> Result<()> {
+    let content = match template {
+        Some(template) => fs::read_to_string(template)
+            .await
+            .with_context(|| format!("Failed to read template {}", template.display()))?,
+        None => String::new(),
+    };
+    write_chat_file(path, &content, newest_first).await?;
+    debug_log(&format!("init: created {}", path.display()));
+    Ok(())
+}
+
+/// Writes `content` to the chat file at `path`, keeping the status line it has.
+async fn write_chat_file(path: &Path, content: &str, newest_first: bool) -> Result<()> {
+    let status = current_status_line(path).await;
+    write_chat_file_with_status(path, content, newest_first, status.as_deref()).await
+}
+
+/// Writes `content` to the chat file at `path` with the status line `status`.
+async fn write_chat_file_with_status(
+    path: &Path,
+    content: &str,
+    newest_first: bool,
+    status: Option<&str>,
+) -> Result<()> {
+    replace_file(path, &with_status_line(to_file_order(content, newest_first), status)).await
+}
+
+/// Replaces `path` with `content` by writing a temp file alongside it and renaming it into
+/// place, so a crash mid-write never leaves a truncated transcript behind.
+async fn replace_file(path: &Path, content: &str) -> Result<()> {
+    let tmp = chat_dir(path).join(format!(".{}.tmp", file_name(path)));
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Exists while a reply is being fetched and written, holding the byte offset where the
+/// reply starts. Finding one at startup means the last run died mid-reply.
+fn pending_marker(chat_path: &Path) -> PathBuf {
+    chat_dir(chat_path).join(format!(".{}.pending", file_name(chat_path)))
+}
+
+/// The autosaved copy of the turn being typed, e.g. `chat.md.draft` for `chat.md`.
+fn draft_path(chat_path: &Path) -> PathBuf {
+    chat_path.with_file_name(format!("{}.draft", file_name(chat_path)))
+}
+
+/// Where the open turn of `content` starts, and its role. `known` is the text before the open
+/// turn and its role from an earlier call: while `content` still starts with it, only what
+/// follows is parsed, so typing into a long file doesn't cost a pass over all of it.
+fn open_turn(content: &str, known: Option<&(String, Role)>) -> (usize, Role) {
+    let (front_matter, _) = split_front_matter(content);
+    let (start, role) = match known {
+        // The first turn can still turn into a system block, so only a later one is reused
+        Some((before, role)) if before.len() > front_matter.len() && content.starts_with(before.as_str()) => {
+            (before.len(), *role)
+        }
+        _ => {
+            let last = split_turns(content).pop().expect("content always has a turn");
+            return (last.start, last.role);
+        }
+    };
+    let bounds = turn_bounds(content, start);
+    let added = bounds.len() - 1;
+    let role = match (role, added % 2) {
+        _ if added == 0 => role,
+        (Role::User, 1) | (Role::Assistant | Role::System, 0) => Role::Assistant,
+        _ => Role::User,
+    };
+    (bounds[added].0, role)
+}
+
+/// The text of the open turn `text`, without notes; `None` when it is blank or a command.
+/// With `reply_file` every turn of the input file is the user's, so the last one is taken
+/// whatever its role.
+fn open_turn_text(text: &str, role: Role, reply_file: bool) -> Option<String> {
+    if !reply_file && role != Role::User {
+        return None;
+    }
+    let text = strip_notes(text);
+    let text = text.trim();
+    (!text.is_empty() && Command::parse(text).is_none()).then(|| text.to_string())
+}
+
+/// Puts back a draft the previous run saved but never sent. It goes into the open turn when
+/// that is blank, without the blank line that would send it; a draft already in the file is
+/// dropped, and one that conflicts with what the open turn holds is set aside as
+/// `<chat>.draft.old`.
+async fn recover_draft(chat_path: &Path, reply_file: bool, newest_first: bool) -> Result<()> {
+    let path = draft_path(chat_path);
+    let Ok(saved) = fs::read_to_string(&path).await else {
+        return Ok(());
+    };
+    let saved = saved.trim();
+    let content = read_chat_file(chat_path, newest_first).await.unwrap_or_default();
+    if saved.is_empty() || content.contains(saved) {
+        fs::remove_file(&path).await?;
+        return Ok(());
+    }
+
+    let turns = split_turns(&content);
+    match turns.last().filter(|turn| reply_file || turn.role == Role::User) {
+        Some(last) if last.text.trim().is_empty() => {
+            let updated = format!("{}{}\n", &content[..last.start], saved);
+            write_chat_file(chat_path, &updated, newest_first).await?;
+            fs::remove_file(&path).await?;
+            debug_log(&format!(
+                "load: restored an unsent draft ({} chars) from {}",
+                saved.chars().count(),
+                file_name(&path)
+            ));
+        }
+        _ => {
+            let old = path.with_file_name(format!("{}.old", file_name(&path)));
+            fs::rename(&path, &old).await?;
+            debug_log(&format!(
+                "skip: an unsent draft doesn't fit the open turn, kept it in {}",
+                old.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Deals with a reply the previous run never finished: text written after the user's turn
+/// without a closing separator is marked incomplete, otherwise a note offers a resend.
+async fn recover_interrupted_reply(chat_path: &Path, newest_first: bool) -> Result<()> {
+    let marker = pending_marker(chat_path);
+    let Ok(offset) = fs::read_to_string(&marker).await else {
+        return Ok(());
+    };
+    fs::remove_file(&marker).await?;
+    let Ok(offset) = offset.trim().parse::<usize>() else {
+        return Ok(());
+    };
+
+    let content = read_chat_file(chat_path, newest_first).await.unwrap_or_default();
+    let Some(partial) = content.get(offset..) else {
+        debug_log("skip: chat changed since the interrupted reply, leaving it alone");
+        return Ok(());
+    };
+    if content.trim_end().ends_with(separator().trim()) && !partial.trim().is_empty() {
+        // The reply was written; only clearing the marker was missed
+        return Ok(());
+    }
+
+    let partial = partial.trim_start().strip_prefix(separator().trim()).unwrap_or(partial);
+    let updated = if partial.trim().is_empty() {
+        debug_log("skip: the last reply was interrupted before anything was written");
+        format!(
+            "{}\n\n{}\n",
+            content.trim_end(),
+            note_block("the reply was interrupted by a restart; press Enter twice again to resend")
+        )
+    } else {
+        debug_log("skip: the last reply was interrupted, marking it incomplete");
+        format!("{}\n\n_(incomplete: interrupted by a restart)_{}", content.trim_end(), separator())
+    };
+    write_chat_file(chat_path, &updated, newest_first).await
+}
+
+async fn backup_chat_file(chat_path: &Path, content: &str, newest_first: bool) -> Result<PathBuf> {
+    let backup_dir = chat_dir(chat_path).join(BACKUP_DIR);
+    fs::create_dir_all(&backup_dir).await?;
+    let mut millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    // Two backups in the same millisecond still sort in the order they were made
+    let mut path = backup_dir.join(format!("{}.{:013}", file_name(chat_path), millis));
+    while fs::try_exists(&path).await? {
+        millis += 1;
+        path = backup_dir.join(format!("{}.{:013}", file_name(chat_path), millis));
+    }
+    fs::write(&path, to_file_order(content, newest_first)).await?;
+    Ok(path)
+}
+
+async fn latest_backup(chat_path: &Path) -> Result<Option<PathBuf>> {
+    let mut entries = match fs::read_dir(chat_dir(chat_path).join(BACKUP_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let prefix = format!("{}.", file_name(chat_path));
+    let mut latest: Option<PathBuf> = None;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_backup = entry.file_name().to_string_lossy().starts_with(&prefix);
+        // Timestamps are zero-padded, so the lexically greatest name is the newest
+        if is_backup && latest.as_ref().is_none_or(|newest| newest.file_name() < path.file_name()) {
+            latest = Some(path);
+        }
+    }
+    Ok(latest)
+}
+
+/// Tidies the stored copy of the turn being sent: trailing spaces are dropped and runs of blank
+/// lines collapse to one, except inside code fences. Running it twice changes nothing.
+fn normalize_last_turn(content: &str) -> String {
+    let start = split_turns(content).last().map_or(0, |turn| turn.start);
+    let mut lines: Vec<&str> = Vec::new();
+    let mut open_fence: Option<(char, usize)> = None;
+
+    for line in content[start..].lines() {
+        let trimmed = line.trim();
+        match open_fence {
+            Some(open) => {
+                if closes_fence(trimmed, open) {
+                    open_fence = None;
+                }
+                lines.push(line);
+            }
+            None => {
+                open_fence = fence_marker(trimmed);
+                let line = line.trim_end();
+                if !(line.is_empty() && lines.last().is_none_or(|last| last.is_empty())) {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+
+    format!("{}{}\n", &content[..start], lines.join("\n").trim_end())
+}
+
+/// Trims a reply and collapses runs of blank lines to one outside code blocks. A line that
+/// would read as a separator is escaped so the reply stays a single turn.
+fn canonical_reply(reply: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut open_fence: Option<(char, usize)> = None;
+
+    for line in reply.trim().lines() {
+        let trimmed = line.trim();
+        match open_fence {
+            Some(open) => {
+                if closes_fence(trimmed, open) {
+                    open_fence = None;
+                }
+                lines.push(line.to_string());
+            }
+            None => {
+                open_fence = fence_marker(trimmed);
+                if trimmed.is_empty() && lines.last().is_some_and(|last| last.trim().is_empty()) {
+                    continue;
+                }
+                if is_separator_line(trimmed) {
+                    lines.push(format!("\\{}", trimmed));
+                } else {
+                    lines.push(line.to_string());
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+/// Writes a reply after the user's turn with exactly one separator before it and `ending`
+/// after it, whatever whitespace or separator `content` already ends with.
+fn append_turn(content: &str, reply: &str, ending: ReplyEnding) -> String {
+    let mut user_turn = content.trim_end();
+    if let Some(stripped) = user_turn.strip_suffix(separator().trim_end()) {
+        user_turn = stripped.trim_end();
+    }
+    format!("{}{}{}{}", user_turn, separator(), canonical_reply(reply), ending.suffix())
+}
+
+/// Drops the blank turns left between two adjacent separators, e.g. by a separator typed
+/// again after a reply, which would otherwise shift every later turn's role. The open turn
+/// at the end stays, blank or not. Returns the content and how many turns were dropped;
+/// `None` when there are none.
+fn collapse_separators(content: &str) -> Option<(String, usize)> {
+    let turns = split_turns(content);
+    let mut collapsed = String::with_capacity(content.len());
+    let mut kept_to = 0;
+    let mut dropped = 0;
+    for pair in turns.windows(2).take(turns.len().saturating_sub(2)) {
+        let (previous, blank) = (&pair[0], &pair[1]);
+        if blank.text.trim().is_empty() {
+            // The separator before the blank turn goes along with it
+            let previous_end = previous.start + previous.text.len();
+            collapsed.push_str(&content[kept_to..previous_end]);
+            kept_to = blank.start + blank.text.len();
+            dropped += 1;
+        }
+    }
+    if dropped == 0 {
+        return None;
+    }
+    collapsed.push_str(&content[kept_to..]);
+    Some((collapsed, dropped))
+}
+
+/// Splits the open user turn wherever `gap` or more blank lines (outside code blocks) sit
+/// between two paragraphs, for users who start a new message without a separator. Each
+/// split gets a note-only filler turn so roles keep alternating. `None` when nothing splits.
+fn split_long_gaps(content: &str, gap: usize) -> Option<String> {
+    let open = split_turns(content).pop().filter(|turn| turn.role == Role::User)?;
+    let mut pieces = Vec::new();
+    let mut piece_start = open.start;
+    let mut offset = open.start;
+    let mut blank_run: Option<(usize, usize)> = None;
+    let mut has_text = false;
+    let mut open_fence: Option<(char, usize)> = None;
+
+    for line in open.text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        match open_fence {
+            Some(fence) => {
+                if closes_fence(trimmed, fence) {
+                    open_fence = None;
+                }
+            }
+            None if trimmed.is_empty() => {
+                let (start, count) = blank_run.unwrap_or((offset, 0));
+                blank_run = Some((start, count + 1));
+            }
+            None => {
+                if let Some((start, count)) = blank_run.take() {
+                    if count >= gap && has_text {
+                        pieces.push(&content[piece_start..start]);
+                        piece_start = offset;
+                    }
+                }
+                has_text = true;
+                open_fence = fence_marker(trimmed);
+            }
+        }
+        offset += line.len();
+    }
+    if pieces.is_empty() {
+        return None;
+    }
+
+    let filler = format!(
+        "{}{}{}",
+        separator(),
+        note_block("no reply: separator inserted by CHAT_AUTO_SEPARATOR"),
+        separator()
+    );
+    let mut split = content[..open.start].to_string();
+    for piece in &pieces {
+        split.push_str(piece.trim_end());
+        split.push_str(&filler);
+    }
+    split.push_str(&content[piece_start..]);
+    debug_log(&format!("add: inserted {} separator(s) at long blank-line gaps", pieces.len()));
+    Some(split)
+}
+
+/// Drops the pending send trigger so a restored state doesn't immediately re-send.
+fn without_trigger(content: &str) -> String {
+    let trimmed = content.trim_end();
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", trimmed)
+    }
+}
+
+/// Set in `--once` mode, where stdout carries only the reply.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// The spinner `with_spinner` is drawing on stdout, if any.
+static SPINNER: std::sync::Mutex<Option<indicatif::WeakProgressBar>> = std::sync::Mutex::new(None);
+
+/// Colors only when logs go to a terminal, following the `NO_COLOR`, `CLICOLOR` and
+/// `CLICOLOR_FORCE` conventions.
+fn configure_colors() {
+    use std::io::IsTerminal;
+
+    let set = |name: &str| std::env::var(name).is_ok_and(|value| !value.is_empty() && value != "0");
+    let enabled = if set("CLICOLOR_FORCE") {
+        true
+    } else if std::env::var("NO_COLOR").is_ok_and(|value| !value.is_empty())
+        || std::env::var("CLICOLOR").is_ok_and(|value| value == "0")
+    {
+        false
+    } else if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    colored::control::set_override(enabled);
+}
+
+fn debug_log(message: &str) {
+    use colored::Colorize;
+    
+    let prefixes = [
+        ("error", ("❌", "red")),
+        ("skip", ("⏭️", "yellow")),
+        ("parse", ("🔍", "cyan")),
+        ("add", ("➕", "green")),
+        ("call", ("🌐", "blue")),
+        ("response", ("✉️", "magenta")),
+        ("detect", ("👀", "cyan")),
+        ("write", ("✍️", "green")),
+        ("init", ("🚀", "green")),
+        ("load", ("📂", "blue")),
+        ("trim", ("✂️", "yellow")),
+        ("unchanged", ("🔄", "yellow")),
+        ("monitoring", ("👁️", "cyan")),
+        ("command", ("⚙️", "blue")),
+    ];
+
+    let (prefix, color) = prefixes
+        .iter()
+        .find(|(key, _)| message.to_lowercase().contains(key))
+        .map(|(_, (emoji, color))| (*emoji, *color))
+        .unwrap_or(("💬", "white"));
+
+    let colored_message = match color {
+        "red" => message.red(),
+        "yellow" => message.yellow(),
+        "cyan" => message.cyan(),
+        "green" => message.green(),
+        "blue" => message.blue(),
+        "magenta" => message.magenta(),
+        _ => message.white(),
+    };
+
+    if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{} {}", prefix, colored_message);
+        return;
+    }
+    // Printed while the spinner is off its line, or the two garble each other
+    let spinner = SPINNER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().and_then(|spinner| spinner.upgrade());
+    match spinner {
+        Some(spinner) => spinner.suspend(|| println!("{} {}", prefix, colored_message)),
+        None => println!("{} {}", prefix, colored_message),
+    }
+}
+
+/// Renders the assembled context one line per message, truncated for readability.
+fn format_preview(messages: &[Message]) -> String {
+    let mut lines = vec![format!("context preview: {} messages + your next message", messages.len())];
+    for message in messages {
+        lines.push(format!("[{}] {}", message.role, one_line(&message.content, PREVIEW_CHARS)));
+    }
+    lines.join("\n")
+}
+
+/// `text` on a single line, cut to `max_chars` with an ellipsis.
+fn one_line(text: &str, max_chars: usize) -> String {
+    let flat = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if flat.chars().count() > max_chars {
+        format!("{}…", flat.chars().take(max_chars).collect::<String>())
+    } else {
+        flat
+    }
+}
+
+/// Whether `path` is a file written alongside the chat rather than by a checkout: this tool's
+/// temp files, backups and sidecars (`.chat.md.tmp`, `.chat-backups`, `chat.md.sig`,
+/// `chat.reply.md`, ...) or an editor's swap and backup files. None of them count toward
+/// `CHAT_BULK_FILES`.
+fn is_incidental_file(path: &Path, chat_path: &Path) -> bool {
+    let name = file_name(path);
+    let sidecars = [
+        draft_path(chat_path),
+        draft_path(chat_path).with_extension("draft.old"),
+        signature_path(chat_path),
+        reply_path(chat_path),
+        usage_path(chat_path),
+    ];
+    // Hidden files cover the pending marker, the conversation ID and temp files as well as
+    // vim's `.chat.md.swp` and emacs's `.#chat.md`; `4913` is vim's check that it may write
+    // to the directory
+    name.starts_with('.')
+        || name.starts_with('#')
+        || name.ends_with('~')
+        || name == "4913"
+        || sidecars.iter().any(|sidecar| file_name(sidecar) == name)
+}
+
+/// The `CHAT_REPLY_FILE` output for a chat, e.g. `chat.reply.md` for `chat.md`.
+fn reply_path(chat_path: &Path) -> PathBuf {
+    chat_path.with_extension("reply.md")
+}
+
+/// With `CHAT_REPLY_FILE`, the conversation as if the replies were inline: each non-empty
+/// turn of the user-only `input` followed by the reply to it, in order. An unanswered last
+/// turn is kept verbatim so the send trigger still works; once answered, an empty user turn
+/// is left open.
+fn merge_replies(input: &str, replies: &str) -> String {
+    let (front_matter, _) = split_front_matter(input);
+    let turns = split_turns(input);
+    let mut replies = split_turns(replies)
+        .into_iter()
+        .map(|turn| turn.text.trim())
+        .filter(|text| !text.is_empty());
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut answered = false;
+    let last = turns.len().saturating_sub(1);
+    for (i, turn) in turns.iter().enumerate() {
+        if i == 0 && system_text(turn.text).is_some() {
+            segments.push(turn.text.trim());
+            continue;
+        }
+        if strip_notes(turn.text).trim().is_empty() {
+            if i == last {
+                segments.push(turn.text);
+            }
+            continue;
+        }
+        let reply = replies.next();
+        answered = reply.is_some();
+        segments.push(if i == last && !answered { turn.text } else { turn.text.trim() });
+        segments.extend(reply);
+    }
+    if answered {
+        segments.push("");
+    }
+    format!("{}{}", front_matter, segments.join(separator()))
+}
+
+/// The chat as processed: the file itself, or merged with its replies under `CHAT_REPLY_FILE`.
+async fn read_conversation(path: &Path, reply_file: bool, newest_first: bool) -> Result<String> {
+    let content = read_chat_file(path, newest_first).await?;
+    if !reply_file {
+        return Ok(content);
+    }
+    let replies = read_chat_file(&reply_path(path), newest_first).await.unwrap_or_default();
+    Ok(merge_replies(&content, &replies))
+}
+
+/// `<chat>.sig` beside the chat file: one hash per finished turn, each over the previous
+/// hash and the turn's text, so changing any turn breaks the chain from there on.
+fn signature_path(chat_path: &Path) -> PathBuf {
+    chat_path.with_file_name(format!("{}.sig", file_name(chat_path)))
+}
+
+fn chain_hash(previous: &str, text: &str) -> String {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(previous.as_bytes());
+    hasher.update(text.trim().as_bytes());
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Chains `turns` on from the hash `from` (empty for the first turn).
+fn turn_chain<'a>(turns: impl Iterator<Item = &'a str>, from: &str) -> Vec<String> {
+    let mut previous = from.to_string();
+    turns
+        .map(|text| {
+            previous = chain_hash(&previous, text);
+            previous.clone()
+        })
+        .collect()
+}
+
+/// Every turn but the open one at the end.
+fn finished_turns(content: &str) -> Vec<&str> {
+    let mut turns: Vec<&str> = split_turns(content).into_iter().map(|turn| turn.text).collect();
+    turns.pop();
+    turns
+}
+
+async fn read_signature(chat_path: &Path) -> Option<Vec<String>> {
+    let chain = fs::read_to_string(signature_path(chat_path)).await.ok()?;
+    Some(chain.lines().map(str::to_string).collect())
+}
+
+async fn write_signature(chat_path: &Path, chain: &[String]) -> Result<()> {
+    let path = signature_path(chat_path);
+    let lines: String = chain.iter().map(|hash| format!("{}\n", hash)).collect();
+    fs::write(&path, lines)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Signs every finished turn afresh, for a new record or a rewrite made by a command.
+async fn sign_turns(chat_path: &Path, content: &str) -> Result<()> {
+    write_signature(chat_path, &turn_chain(finished_turns(content).into_iter(), "")).await
+}
+
+/// Adds the turns finished since the last signing to the chain, leaving the signed ones as
+/// they were recorded.
+async fn extend_signature(chat_path: &Path, content: &str) -> Result<()> {
+    let Some(mut chain) = read_signature(chat_path).await else {
+        return sign_turns(chat_path, content).await;
+    };
+    let turns = finished_turns(content);
+    let from = chain.last().cloned().unwrap_or_default();
+    chain.extend(turn_chain(turns.into_iter().skip(chain.len()), &from));
+    write_signature(chat_path, &chain).await
+}
+
+/// The first signed turn (0-based) that no longer matches the chain, counting a removed
+/// turn as changed. A file without a chain starts one and counts as intact.
+async fn altered_turn(chat_path: &Path, content: &str) -> Result<Option<usize>> {
+    let Some(chain) = read_signature(chat_path).await else {
+        debug_log(&format!(
+            "init: signing {} turns into {}",
+            finished_turns(content).len(),
+            signature_path(chat_path).display()
+        ));
+        sign_turns(chat_path, content).await?;
+        return Ok(None);
+    };
+    let current = turn_chain(finished_turns(content).into_iter(), "");
+    Ok((0..chain.len()).find(|&i| current.get(i) != Some(&chain[i])))
+}
+
+/// Signs `content` again after the monitor itself rewrote it. A chain an outside edit already
+/// broke (`altered`) is only extended, so that edit is still reported.
+async fn resign(chat_path: &Path, content: &str, altered: Option<usize>) -> Result<()> {
+    match altered {
+        Some(_) => extend_signature(chat_path, content).await,
+        None => sign_turns(chat_path, content).await,
+    }
+}
+
+/// Logs an alteration of the signed turns once, until a different turn is found altered.
+async fn check_signature(chat_path: &Path, content: &str, warned: &mut Option<usize>) -> Result<()> {
+    let altered = altered_turn(chat_path, content).await?;
+    if let Some(turn) = altered.filter(|turn| *warned != Some(*turn)) {
+        debug_log(&format!(
+            "error: turn {} of {} was changed outside chat-md since it was signed in {}",
+            turn + 1,
+            chat_path.display(),
+            signature_path(chat_path).display()
+        ));
+    }
+    *warned = altered;
+    Ok(())
+}
+
+/// Adds a reply to the reply file, each one followed by a separator.
+fn append_reply(replies: &str, reply: &str) -> String {
+    let (front_matter, body) = split_front_matter(replies);
+    if body.trim().is_empty() {
+        format!("{}{}{}", front_matter, canonical_reply(reply), separator())
+    } else {
+        append_turn(replies, reply, ReplyEnding::Separator)
+    }
+}
+
+/// The usage sidecar for a chat, e.g. `chat.usage.json` for `chat.md`.
+fn usage_path(chat_path: &Path) -> PathBuf {
+    chat_path.with_extension("usage.json")
+}
+
+async fn read_usage(chat_path: &Path) -> Result<Vec<UsageRecord>> {
+    let path = usage_path(chat_path);
+    match fs::read_to_string(&path).await {
+        Ok(json) => serde_json::from_str(&json).with_context(|| format!("{} is not valid", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn record_usage(chat_path: &Path, model: &str, usage: &Usage) -> Result<()> {
+    let mut records = read_usage(chat_path).await?;
+    records.push(UsageRecord {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        model: model.to_string(),
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+    });
+    fs::write(usage_path(chat_path), serde_json::to_string_pretty(&records)?).await?;
+    Ok(())
+}
+
+/// Per-turn token counts with running totals, and a running cost when prices are set.
+fn usage_table(records: &[UsageRecord], prices: Option<(f64, f64)>) -> String {
+    if records.is_empty() {
+        return "no usage recorded yet".to_string();
+    }
+    let mut lines = match prices {
+        Some(_) => vec![
+            "| turn | model | prompt | completion | total cost |".to_string(),
+            "| ---: | --- | ---: | ---: | ---: |".to_string(),
+        ],
+        None => vec![
+            "| turn | model | prompt | completion |".to_string(),
+            "| ---: | --- | ---: | ---: |".to_string(),
+        ],
+    };
+    let (mut prompt, mut completion) = (0, 0);
+    for (i, record) in records.iter().enumerate() {
+        prompt += record.prompt_tokens;
+        completion += record.completion_tokens;
+        let mut row = format!(
+            "| {} | {} | {} | {} |",
+            i + 1,
+            record.model,
+            record.prompt_tokens,
+            record.completion_tokens
+        );
+        if let Some(prices) = prices {
+            row.push_str(&format!(" ${:.4} |", cost(prompt, completion, prices)));
+        }
+        lines.push(row);
+    }
+    let mut total = format!("| total | | {} | {} |", prompt, completion);
+    if let Some(prices) = prices {
+        total.push_str(&format!(" ${:.4} |", cost(prompt, completion, prices)));
+    }
+    lines.push(total);
+    lines.join("\n")
+}
+
+fn cost(prompt: u64, completion: u64, (prompt_price, completion_price): (f64, f64)) -> f64 {
+    (prompt as f64 * prompt_price + completion as f64 * completion_price) / 1_000_000.0
+}
+
+/// A new, timestamped chat file next to `path`, e.g. `chat-fork-1700000000.md`.
+fn sibling_chat(path: &Path, kind: &str) -> Result<PathBuf> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(path.with_file_name(format!("{}-{}-{}.md", stem, kind, secs)))
+}
+
+/// Re-sends every user turn of `content` (except the open one with the command) in order,
+/// building the context from the fresh replies rather than the old ones. Returns the new
+/// conversation and how many turns were replayed.
+async fn replay(
+    content: &str,
+    chat_context: &ChatContext,
+    api_client: &ApiClient,
+    config: &Config,
+) -> Result<(String, usize)> {
+    let (front_matter, _) = split_front_matter(content);
+    let mut segments = split_turns(content);
+    segments.pop();
+
+    let mut kept = Vec::new();
+    let mut system = None;
+    let mut transcript: Vec<Message> = Vec::new();
+    for turn in segments {
+        match turn.role {
+            Role::System => {
+                system = system_text(turn.text).map(|text| Message::new(Role::System, text));
+                kept.push(turn.text.trim().to_string());
+            }
+            Role::User => {
+                let text = message_text(turn.text, config.user_wrapper.as_ref());
+                if text.is_empty() {
+                    continue;
+                }
+                let recent = transcript.len().saturating_sub(chat_context.max_messages);
+                let history = system.iter().cloned().chain(transcript[recent..].iter().cloned()).collect();
+                let mut messages = chat_context.assemble(history, api_client.instruction_role());
+                messages.push(chat_context.user_message(text.clone()));
+
+                debug_log(&format!("call: replaying turn {}", transcript.len() / 2 + 1));
+                let reply = api_client.call_model(&api_client.provider.model, messages, false).await?;
+                kept.push(turn.text.trim().to_string());
+                kept.push(canonical_reply(&reply.content));
+                transcript.push(Message::new(Role::User, text));
+                transcript.push(Message::new(Role::Assistant, reply.content.trim()));
+            }
+            _ => {}
+        }
+    }
+    if transcript.is_empty() {
+        anyhow::bail!("No user turns to replay");
+    }
+    Ok((format!("{}{}{}", front_matter, kept.join(separator()), separator()), transcript.len() / 2))
+}
+
+/// Sends the conversation up to the last user message to every bench model at once and
+/// tabulates how each one did.
+async fn bench(api_client: &ApiClient, models: &[String], history: &[Message]) -> Result<String> {
+    let last_user = history
+        .iter()
+        .rposition(|message| message.role == Role::User)
+        .context("No user message to bench")?;
+    let messages = &history[..=last_user];
+
+    debug_log(&format!("command: benching {} models", models.len()));
+    let runs = models.iter().map(|model| async move {
+        let started = Instant::now();
+        let result = api_client.call_model(model, messages.to_vec(), false).await;
+        (model, started.elapsed(), result)
+    });
+
+    let mut rows = vec![
+        "| model | latency | tokens (prompt/completion) | reply |".to_string(),
+        "|---|---|---|---|".to_string(),
+    ];
+    for (model, elapsed, result) in futures::future::join_all(runs).await {
+        let (tokens, reply) = match result {
+            Ok(completion) => (
+                completion.usage.map_or("-".to_string(), |usage| {
+                    format!("{}/{}", usage.prompt_tokens, usage.completion_tokens)
+                }),
+                one_line(&completion.content, BENCH_PREVIEW_CHARS),
+            ),
+            Err(e) => ("-".to_string(), format!("error: {:#}", e)),
+        };
+        rows.push(format!(
+            "| {} | {:.2}s | {} | {} |",
+            model,
+            elapsed.as_secs_f64(),
+            tokens,
+            reply.replace('|', "\\|")
+        ));
+    }
+    Ok(rows.join("\n"))
+}
+
+/// Sends the conversation up to the last user message to two models and shows their replies
+/// as one word-level diff: words only the first used are struck through, words only the
+/// second used are bold.
+async fn diff_replies(api_client: &ApiClient, first: &str, second: &str, history: &[Message]) -> Result<String> {
+    let last_user = history
+        .iter()
+        .rposition(|message| message.role == Role::User)
+        .context("No user message to diff replies for")?;
+    let messages = &history[..=last_user];
+
+    debug_log(&format!("command: diffing replies from {} and {}", first, second));
+    let (old, new) = futures::future::join(
+        api_client.call_model(first, messages.to_vec(), false),
+        api_client.call_model(second, messages.to_vec(), false),
+    )
+    .await;
+    let (old, new) = (old?.content, new?.content);
+
+    let diff = similar::TextDiff::configure().timeout(WORD_DIFF_TIMEOUT).diff_words(old.trim(), new.trim());
+    let shared = diff
+        .iter_all_changes()
+        .filter(|change| change.tag() == similar::ChangeTag::Equal && !change.value().trim().is_empty())
+        .count();
+    let most = old.split_whitespace().count().max(new.split_whitespace().count());
+    Ok(format!(
+        "~~{}~~ vs **{}**, {}% of words in common\n\n{}",
+        first,
+        second,
+        100 * shared / most.max(1),
+        render_word_diff(&diff)
+    ))
+}
+
+/// Joins the diff back into the second text, wrapping each run of removed words in `~~` and
+/// each run of added words in `**`. Whitespace is taken from the second text and never marked.
+fn render_word_diff(diff: &similar::TextDiff<str>) -> String {
+    let mut out = String::new();
+    let mut run = String::new();
+    let mut marker = "";
+    for change in diff.iter_all_changes() {
+        let word = change.value();
+        let kind = match change.tag() {
+            // Whitespace is kept after removed words too, so they don't run into the next one
+            _ if word.trim().is_empty() => {
+                if change.tag() != similar::ChangeTag::Delete || marker == "~~" {
+                    run.push_str(word);
+                }
+                continue;
+            }
+            similar::ChangeTag::Equal => "",
+            similar::ChangeTag::Delete => "~~",
+            similar::ChangeTag::Insert => "**",
+        };
+        if kind != marker {
+            // A replaced word is struck through and followed by its replacement, not joined to it
+            if marker == "~~" && !run.ends_with(char::is_whitespace) {
+                run.push(' ');
+            }
+            close_run(&mut out, &mut run, marker);
+            marker = kind;
+        }
+        run.push_str(word);
+    }
+    close_run(&mut out, &mut run, marker);
+    out.trim_end().to_string()
+}
+
+/// Appends a run of words to `out`, marked up but with its trailing whitespace outside.
+fn close_run(out: &mut String, run: &mut String, marker: &str) {
+    let text = run.trim_end();
+    if !text.is_empty() {
+        out.push_str(&format!("{}{}{}{}", marker, text, marker, &run[text.len()..]));
+    }
+    run.clear();
+}
+
+async fn run_command(
+    command: Command,
+    content_before: &str,
+    history: &[Message],
+    chat_context: &mut ChatContext,
+    api_client: &ApiClient,
+    config: &Config,
+) -> Result<CommandOutcome> {
+    match command {
+        Command::Share => {
+            let target = config
+                .share
+                .as_ref()
+                .context("Set CHAT_SHARE_URL or GITHUB_TOKEN to enable /share")?;
+            let name = file_name(&chat_context.path);
+            let url = share(&api_client.client, target, &name, &strip_scratch(content_before)).await?;
+            debug_log(&format!("command: shared {} at {}", name, url));
+            Ok(CommandOutcome::Note(format!("shared: {}", url)))
+        }
+        Command::Complete => {
+            let draft = split_turns(content_before)
+                .last()
+                .filter(|turn| turn.role == Role::User)
+                .map(|turn| message_text(turn.text, config.user_wrapper.as_ref()))
+                .filter(|draft| !draft.is_empty())
+                .context("Nothing to complete: write some text above /complete")?;
+
+            let mut messages = history.to_vec();
+            messages.push(Message::new(api_client.instruction_role(), COMPLETE_PROMPT));
+            messages.push(chat_context.user_message(draft));
+            let continuation = api_client.call_api(messages, false).await?.content;
+
+            let path = backup_chat_file(&chat_context.path, content_before, config.newest_first).await?;
+            chat_context.redo_stack.clear();
+            debug_log(&format!("command: completed draft, backup at {}", path.display()));
+            // No blank line after it, so the draft stays open for more editing
+            Ok(CommandOutcome::Restore(format!(
+                "{}{}\n",
+                content_before.trim_end(),
+                continuation.trim_end()
+            )))
+        }
+        Command::Bench => Ok(CommandOutcome::Note(bench(api_client, &config.bench_models, history).await?)),
+        Command::DiffReplies(arg) => {
+            let models: Vec<String> = match arg.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => config.bench_models.iter().take(2).cloned().collect(),
+                [first, second] => vec![first.to_string(), second.to_string()],
+                _ => anyhow::bail!("Usage: /diff-replies [model model]"),
+            };
+            let [first, second] = &models[..] else {
+                anyhow::bail!("/diff-replies needs two models: name them or set CHAT_BENCH_MODELS");
+            };
+            Ok(CommandOutcome::Scratch(diff_replies(api_client, first, second, history).await?))
+        }
+        Command::Endpoint(url) if url.is_empty() => Ok(CommandOutcome::Note(format!(
+            "endpoint: {}",
+            api_client.endpoint()
+        ))),
+        Command::Endpoint(url) => {
+            let url = parse_endpoint(&url)?;
+            debug_log(&format!("command: switching endpoint to {}", url));
+            api_client.set_endpoint(url.clone());
+            Ok(CommandOutcome::Note(format!("endpoint: {}", url)))
+        }
+        Command::Preview => {
+            debug_log(&format!("command: previewing {} messages", history.len()));
+            Ok(CommandOutcome::Note(format_preview(history)))
+        }
+        Command::Undo => {
+            let path = latest_backup(&chat_context.path).await?.context("Nothing to undo")?;
+            let restored = read_chat_file(&path, config.newest_first).await?;
+            fs::remove_file(&path).await?;
+            debug_log(&format!("command: undo restored {}", path.display()));
+
+            chat_context
+                .redo_stack
+                .push((restored.clone(), without_trigger(content_before)));
+            Ok(CommandOutcome::Restore(without_trigger(&restored)))
+        }
+        Command::Redo => {
+            let (backup, state) = chat_context.redo_stack.pop().context("Nothing to redo")?;
+            let path = backup_chat_file(&chat_context.path, &backup, config.newest_first).await?;
+            debug_log(&format!("command: redo re-applied, backup at {}", path.display()));
+            Ok(CommandOutcome::Restore(state))
+        }
+        Command::Plain(arg) => {
+            if let Some(enabled) = parse_toggle(&arg)? {
+                chat_context.plain_text = enabled;
+                debug_log(&format!("command: plain text {}", on_off(enabled)));
+            }
+            Ok(CommandOutcome::Note(format!("plain text: {}", on_off(chat_context.plain_text))))
+        }
+        Command::Timing(arg) => {
+            if let Some(enabled) = parse_toggle(&arg)? {
+                chat_context.timing = enabled;
+                debug_log(&format!("command: timing {}", on_off(enabled)));
+            }
+            Ok(CommandOutcome::Note(format!("timing: {}", on_off(chat_context.timing))))
+        }
+        Command::Stream(arg) => {
+            if let Some(enabled) = parse_toggle(&arg)? {
+                chat_context.stream = enabled;
+                debug_log(&format!("command: streaming {}", on_off(enabled)));
+            }
+            Ok(CommandOutcome::Note(format!("streaming: {}", on_off(chat_context.stream))))
+        }
+        Command::Search(arg) => {
+            let enabled = parse_toggle(&arg)?.unwrap_or(true);
+            if enabled && api_client.provider.search_tool.is_none() {
+                debug_log("skip: /search needs CHAT_SEARCH_TOOL, the next turn goes without web search");
+                return Ok(CommandOutcome::Note(
+                    "web search: not available (set CHAT_SEARCH_TOOL to the provider's search tool)".to_string(),
+                ));
+            }
+            chat_context.search = enabled;
+            debug_log(&format!("command: web search {} for the next turn", on_off(enabled)));
+            Ok(CommandOutcome::Note(format!("web search: {} for the next turn", on_off(enabled))))
+        }
+        Command::Chats => {
+            let chats = list_chats(&chat_context.path).await?;
+            debug_log(&format!("command: listing {} chats", chats.len()));
+            Ok(CommandOutcome::Note(chats.join("\n")))
+        }
+        Command::Switch(name) => {
+            if name.is_empty() {
+                anyhow::bail!("Usage: /switch <name>");
+            }
+            if !is_plain_name(&name) {
+                anyhow::bail!("/switch takes the name of a chat next to this one, not a path: {}", name);
+            }
+            let mut target = chat_context.path.with_file_name(&name);
+            if target.extension().is_none() {
+                target.set_extension("md");
+            }
+            if !fs::try_exists(&target).await? {
+                anyhow::bail!("No such chat: {}", target.display());
+            }
+            if is_ignored(&target).await {
+                anyhow::bail!("{} is excluded by {}", target.display(), IGNORE_FILE);
+            }
+
+            debug_log(&format!("command: switching to {}", target.display()));
+            chat_context.path = target.clone();
+            chat_context.redo_stack.clear();
+            Ok(CommandOutcome::Note(format!("switched to {}", target.display())))
+        }
+        Command::Usage => {
+            let records = read_usage(&chat_context.path).await?;
+            debug_log(&format!("command: usage for {} turns", records.len()));
+            Ok(CommandOutcome::Note(usage_table(&records, config.prices)))
+        }
+        Command::Resume => {
+            let was_paused = api_client.error_budget.as_ref().is_some_and(ErrorBudget::resume);
+            debug_log(&format!("command: resume (was paused: {})", was_paused));
+            Ok(CommandOutcome::Note(
+                if was_paused { "resumed sending" } else { "not paused" }.to_string(),
+            ))
+        }
+        Command::Normalize => {
+            let (normalized, found) = normalize_roles(content_before);
+            if found == 0 {
+                anyhow::bail!("No role prefixes such as \"You:\" or \"Assistant:\" found");
+            }
+            let path = backup_chat_file(&chat_context.path, content_before, config.newest_first).await?;
+            chat_context.redo_stack.clear();
+            debug_log(&format!("command: split {} prefixed turns, backup at {}", found, path.display()));
+            let note = note_block(&format!("split {} turns at their role prefixes (undo with /undo)", found));
+            Ok(CommandOutcome::Restore(format!("{}{}{}", normalized, note, DOUBLE_NEWLINE)))
+        }
+        Command::Trim(arg) => {
+            let keep: usize = arg.parse().ok().filter(|&keep| keep > 0).context("Usage: /trim <turns>")?;
+            let (trimmed, dropped) = trim_turns(content_before, keep)?;
+            let path = backup_chat_file(&chat_context.path, content_before, config.newest_first).await?;
+            chat_context.redo_stack.clear();
+            debug_log(&format!("command: trimmed {} turns, backup at {}", dropped, path.display()));
+            let note = note_block(&format!("dropped the first {} turns (undo with /undo)", dropped));
+            Ok(CommandOutcome::Restore(format!("{}{}{}", trimmed, note, DOUBLE_NEWLINE)))
+        }
+        Command::Context(arg) => {
+            if !arg.is_empty() {
+                let max: usize = arg.parse().ok().filter(|&max| max > 0).context("Usage: /context [messages]")?;
+                debug_log(&format!("command: context {} -> {} messages", chat_context.max_messages, max));
+                chat_context.max_messages = max;
+            }
+            Ok(CommandOutcome::Note(format!("context: last {} messages", chat_context.max_messages)))
+        }
+        Command::Pin(arg) => pin_command(content_before, &arg, true),
+        Command::Unpin(arg) => pin_command(content_before, &arg, false),
+        Command::Replay => {
+            let (replayed, turns) = replay(content_before, chat_context, api_client, config).await?;
+            let target = sibling_chat(&chat_context.path, "replay")?;
+            write_chat_file(&target, &replayed, config.newest_first).await?;
+            debug_log(&format!("command: replayed {} turns into {}", turns, target.display()));
+            Ok(CommandOutcome::Note(format!("replayed {} turns into {}", turns, target.display())))
+        }
+        Command::Fork(arg) => {
+            let turns = match arg.strip_prefix("at=") {
+                Some(turn) => Some(turn.trim().parse().context("Usage: /fork [at=N]")?),
+                None if arg.is_empty() => None,
+                None => anyhow::bail!("Usage: /fork [at=N]"),
+            };
+            let forked = conversation_prefix(content_before, turns)?;
+
+            let target = sibling_chat(&chat_context.path, "fork")?;
+            write_chat_file(&target, &forked, config.newest_first).await?;
+
+            debug_log(&format!("command: forked into {}", target.display()));
+            chat_context.path = target.clone();
+            chat_context.redo_stack.clear();
+            Ok(CommandOutcome::Note(format!("forked into {}", target.display())))
+        }
+        Command::Remember(fact) => {
+            if fact.is_empty() {
+                anyhow::bail!("Usage: /remember <fact>");
+            }
+            let path = chat_context.memory_path();
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+            file.write_all(format!("- {}\n", fact).as_bytes()).await?;
+            debug_log(&format!("command: remembered in {}", path.display()));
+            Ok(CommandOutcome::Note(format!("remembered in {}: {}", path.display(), fact)))
+        }
+        Command::Lang(arg) => {
+            match arg.as_str() {
+                "" => {}
+                "off" => chat_context.language = None,
+                language => chat_context.language = Some(language.to_string()),
+            }
+            debug_log(&format!("command: reply language {:?}", chat_context.language));
+            Ok(CommandOutcome::Note(format!(
+                "reply language: {}",
+                chat_context.language.as_deref().unwrap_or("off")
+            )))
+        }
+        Command::Summarize(arg) => {
+            let (first, last) = arg
+                .split_once('-')
+                .and_then(|(first, last)| Some((first.trim().parse().ok()?, last.trim().parse().ok()?)))
+                .context("Usage: /summarize N-M")?;
+            let summarized = summarize_range(content_before, first, last, api_client).await?;
+
+            let path = backup_chat_file(&chat_context.path, content_before, config.newest_first).await?;
+            chat_context.redo_stack.clear();
+            debug_log(&format!(
+                "command: summarized messages {}-{}, backup at {}",
+                first,
+                last,
+                path.display()
+            ));
+            Ok(CommandOutcome::Restore(without_trigger(&summarized)))
+        }
+    }
+}
+
+/// `/pin [N]` and `/unpin [N]`: rewrites the marker and notes which message changed.
+fn pin_command(content_before: &str, arg: &str, pinned: bool) -> Result<CommandOutcome> {
+    let action = if pinned { "pin" } else { "unpin" };
+    let turn = match arg {
+        "" => None,
+        n => Some(n.parse().with_context(|| format!("Usage: /{} [N]", action))?),
+    };
+    let (updated, number) = set_pin(content_before, turn, pinned)?;
+    debug_log(&format!("command: {}ned message {}", action, number));
+    Ok(CommandOutcome::Restore(format!(
+        "{}{}{}",
+        updated,
+        note_block(&format!("{}ned message {}", action, number)),
+        DOUBLE_NEWLINE
+    )))
+}
+
+/// Replaces messages `first..=last` (counted like `/fork at=N`) with one summary turn and
+/// leaves the rest of the file untouched.
+async fn summarize_range(content: &str, first: usize, last: usize, api_client: &ApiClient) -> Result<String> {
+    let available = turn_count(content);
+    if first == 0 || first > last || last > available {
+        anyhow::bail!("Range must be within 1-{}", available);
+    }
+
+    let turns = split_turns(content);
+    let mut selected = Vec::new();
+    let mut count = 0;
+    for (index, turn) in turns.iter().enumerate() {
+        let text = strip_notes(turn.text);
+        let text = text.trim();
+        if text.is_empty() || turn.role == Role::System {
+            continue;
+        }
+        count += 1;
+        if (first..=last).contains(&count) {
+            selected.push((index, format!("{}: {}", turn.role, text)));
+        }
+    }
+    let (start, end) = (selected[0].0, selected[selected.len() - 1].0);
+    let transcript: Vec<String> = selected.into_iter().map(|(_, line)| line).collect();
+
+    let messages = vec![
+        Message::new(Role::System, SUMMARY_PROMPT),
+        Message::new(Role::User, transcript.join("\n\n")),
+    ];
+    let summary = api_client
+        .call_model(&api_client.provider.model, messages, false)
+        .await?
+        .content;
+
+    let mut replacement = format!("**Summary of messages {}-{}:** {}\n", first, last, summary.trim());
+    // Roles come from position, so replacing an even number of turns needs a filler turn to
+    // keep the rest alternating; a note is dropped before sending, so it reads as empty
+    if (end - start + 1) % 2 == 0 {
+        replacement.push_str(&format!(
+            "{}{}\n",
+            separator().trim_start(),
+            note_block(&format!("messages {}-{} summarized above", first, last))
+        ));
+    }
+
+    let end_offset = turns[end].start + turns[end].text.len();
+    Ok(format!(
+        "{}{}{}",
+        &content[..turns[start].start],
+        replacement,
+        &content[end_offset..]
+    ))
+}
+
+/// The role named by a `You:` / `ChatGPT said:` / `**Assistant:**` style prefix at the start
+/// of a pasted line, and the rest of the line.
+fn role_prefix(line: &str) -> Option<(Role, &str)> {
+    static PREFIX: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        regex::Regex::new(
+            r"(?i)^\s*(?:\*\*)?(you|user|human|me|assistant|ai|chatgpt|claude|gemini|bot|model)(?: said)?\s*:(?:\*\*)?\s*",
+        )
+        .expect("valid role prefix pattern")
+    });
+    let found = prefix.captures(line)?;
+    let role = match found[1].to_lowercase().as_str() {
+        "you" | "user" | "human" | "me" => Role::User,
+        _ => Role::Assistant,
+    };
+    Some((role, &line[found[0].len()..]))
+}
+
+/// Keeps the front matter, the system block and the last `keep` finished turns, starting on
+/// a user turn so roles still alternate, followed by the open turn. Returns the content and
+/// how many turns were dropped.
+fn trim_turns(content: &str, keep: usize) -> Result<(String, usize)> {
+    let (front_matter, _) = split_front_matter(content);
+    let turns = split_turns(content);
+    let (open, finished) = turns.split_last().context("Nothing to trim")?;
+    let (system, finished) = match finished.split_first() {
+        Some((first, rest)) if first.role == Role::System => (Some(first), rest),
+        _ => (None, finished),
+    };
+
+    let mut start = finished.len().saturating_sub(keep);
+    if finished.get(start).is_some_and(|turn| turn.role == Role::Assistant) {
+        start += 1;
+    }
+    if start == 0 {
+        anyhow::bail!("Nothing to trim: the conversation has {} turns", finished.len());
+    }
+
+    let parts: Vec<&str> = system
+        .into_iter()
+        .chain(&finished[start..])
+        .map(|turn| turn.text.trim())
+        .chain([open.text.trim()])
+        .collect();
+    let mut trimmed = format!("{}{}", front_matter, parts.join(separator()));
+    if !open.text.trim().is_empty() {
+        trimmed.push_str(DOUBLE_NEWLINE);
+    }
+    Ok((trimmed, start))
+}
+
+/// Splits turns pasted from another tool at their role prefixes, drops the prefixes and
+/// rejoins everything with separators, merging neighbouring turns of the same role. Returns
+/// the conversation and how many prefixes were found.
+fn normalize_roles(content: &str) -> (String, usize) {
+    let (front_matter, _) = split_front_matter(content);
+    let mut system = None;
+    let mut blocks: Vec<(Role, String)> = Vec::new();
+    let mut found = 0;
+
+    for turn in split_turns(content) {
+        if turn.role == Role::System {
+            system = Some(turn.text.trim().to_string());
+            continue;
+        }
+        let mut role = turn.role;
+        let mut current = String::new();
+        let mut open_fence: Option<(char, usize)> = None;
+        for line in turn.text.lines() {
+            let trimmed = line.trim();
+            let prefixed = match open_fence {
+                Some(open) => {
+                    if closes_fence(trimmed, open) {
+                        open_fence = None;
+                    }
+                    None
+                }
+                None => {
+                    open_fence = fence_marker(trimmed);
+                    role_prefix(line)
+                }
+            };
+            match prefixed {
+                Some((next, rest)) => {
+                    found += 1;
+                    blocks.push((role, std::mem::take(&mut current)));
+                    role = next;
+                    current.push_str(rest);
+                    current.push('\n');
+                }
+                None => {
+                    current.push_str(line);
+                    current.push('\n');
+                }
+            }
+        }
+        blocks.push((role, current));
+    }
+
+    let mut merged: Vec<(Role, String)> = Vec::new();
+    for (role, text) in blocks {
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        match merged.last_mut() {
+            Some((last, previous)) if *last == role => {
+                previous.push_str(DOUBLE_NEWLINE);
+                previous.push_str(&text);
+            }
+            _ => merged.push((role, text)),
+        }
+    }
+    // Roles come from position, so a conversation opening with a reply needs a filler turn
+    if merged.first().is_some_and(|(role, _)| *role == Role::Assistant) {
+        merged.insert(0, (Role::User, note_block("conversation imported from another tool")));
+    }
+
+    let mut segments: Vec<String> = system.into_iter().collect();
+    segments.extend(merged.iter().map(|(_, text)| text.clone()));
+    let mut normalized = format!("{}{}", front_matter, segments.join(separator()));
+    if merged.last().is_some_and(|(role, _)| *role == Role::Assistant) {
+        normalized.push_str(separator());
+    } else {
+        normalized.push('\n');
+    }
+    (normalized, found)
+}
+
+/// Lists the markdown files next to the active chat, marking the active one with `*`.
+async fn list_chats(active: &Path) -> Result<Vec<String>> {
+    let mut entries = fs::read_dir(chat_dir(active)).await?;
+    let mut chats = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "md") && entry.file_type().await?.is_file() {
+            chats.push(path);
+        }
+    }
+    let ignored = ignore_patterns(chat_dir(active)).await;
+    chats.retain(|path| !matches_any(&ignored, path));
+    chats.sort();
+
+    let mut lines = Vec::with_capacity(chats.len());
+    for path in chats {
+        let marker = if path.file_name() == active.file_name() { "*" } else { " " };
+        let content = fs::read_to_string(&path).await.unwrap_or_default();
+        match front_matter_title(&content) {
+            Some(title) => lines.push(format!("{} {} - {}", marker, file_name(&path), title)),
+            None => lines.push(format!("{} {}", marker, file_name(&path))),
+        }
+    }
+    Ok(lines)
+}
+
+/// Glob patterns from the `.chatignore` file next to the chats, one per line (`#` starts a
+/// comment). Matching files are left out of `/chats`, cannot be switched to, and are
+/// never sent.
+async fn ignore_patterns(dir: &Path) -> Vec<glob::Pattern> {
+    let Ok(content) = fs::read_to_string(dir.join(IGNORE_FILE)).await else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match glob::Pattern::new(line) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                debug_log(&format!("error: ignoring invalid {} pattern {:?}: {}", IGNORE_FILE, line, e));
+                None
+            }
+        })
+        .collect()
+}
+
+fn matches_any(patterns: &[glob::Pattern], path: &Path) -> bool {
+    let name = file_name(path);
+    patterns.iter().any(|pattern| pattern.matches(&name))
+}
+
+async fn is_ignored(path: &Path) -> bool {
+    matches_any(&ignore_patterns(chat_dir(path)).await, path)
+}
+
+async fn generate_title(api_client: &ApiClient, model: &str, first_message: &str) -> Result<String> {
+    let messages = vec![
+        Message::new(Role::System, TITLE_PROMPT),
+        Message::new(Role::User, first_message),
+    ];
+
+    let title = api_client.call_model(model, messages, false).await?.content;
+    let title = title.lines().next().unwrap_or_default().trim().trim_matches('"');
+    if title.is_empty() {
+        anyhow::bail!("Title generation returned nothing");
+    }
+    Ok(title.chars().take(TITLE_MAX_CHARS).collect())
+}
+
+/// Shows a spinner with the elapsed time while `future` runs. Skipped when stdout isn't a
+/// terminal so piped output stays clean.
+async fn with_spinner<T>(future: impl std::future::Future<Output = T>) -> T {
+    use std::io::IsTerminal;
+
+    if !std::io::stdout().is_terminal() {
+        return future.await;
+    }
+
+    let spinner = indicatif::ProgressBar::with_draw_target(None, indicatif::ProgressDrawTarget::stdout());
+    spinner.set_style(
+        indicatif::ProgressStyle::with_template("{spinner:.blue} waiting for response {elapsed}")
+            .expect("valid spinner template"),
+    );
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    *SPINNER.lock().unwrap_or_else(|e| e.into_inner()) = Some(spinner.downgrade());
+
+    let output = future.await;
+    spinner.finish_and_clear();
+    output
+}
+
+/// Calls the primary model, switching to the fallback model if it misses the soft deadline.
+/// Only the primary model is offered web search.
+async fn call_with_fallback(
+    api_client: &ApiClient,
+    config: &Config,
+    messages: Vec<Message>,
+    search: bool,
+) -> Result<Completion, ChatError> {
+    let Some(fallback_model) = &config.fallback_model else {
+        return api_client.call_api(messages, search).await;
+    };
+
+    tokio::select! {
+        result = api_client.call_api(messages.clone(), search) => result,
+        _ = tokio::time::sleep(config.fallback_after) => {
+            debug_log(&format!(
+                "skip: {} took longer than {}s, retrying with {}",
+                api_client.provider.model,
+                config.fallback_after.as_secs(),
+                fallback_model
+            ));
+            let completion = api_client.call_model(fallback_model, messages, true).await?;
+            Ok(answered_by_fallback(completion, fallback_model, &api_client.provider.model))
+        }
+    }
+}
+
+/// Like `call_with_fallback`, but streamed into `partial`. The fallback model is only asked
+/// when nothing has arrived by the deadline; a reply that has started streaming is finished.
+async fn stream_with_fallback(
+    api_client: &ApiClient,
+    config: &Config,
+    messages: Vec<Message>,
+    partial: &tokio::sync::watch::Sender<String>,
+) -> Result<Completion, ChatError> {
+    let Some(fallback_model) = &config.fallback_model else {
+        return api_client.stream_api(messages, partial).await;
+    };
+
+    let call = api_client.stream_api(messages.clone(), partial);
+    tokio::pin!(call);
+    tokio::select! {
+        result = &mut call => return result,
+        _ = tokio::time::sleep(config.fallback_after) => {}
+    }
+    if !partial.borrow().is_empty() {
+        return call.await;
+    }
+    debug_log(&format!(
+        "skip: {} streamed nothing for {}s, retrying with {}",
+        api_client.provider.model,
+        config.fallback_after.as_secs(),
+        fallback_model
+    ));
+    let completion = api_client.call_model(fallback_model, messages, false).await?;
+    Ok(answered_by_fallback(completion, fallback_model, &api_client.provider.model))
+}
+
+/// Notes under a reply that the fallback model gave it.
+fn answered_by_fallback(mut completion: Completion, fallback_model: &str, model: &str) -> Completion {
+    completion.content = format!(
+        "{}\n\n_(answered by {} after {} timed out)_",
+        completion.content.trim_end(),
+        fallback_model,
+        model
+    );
+    completion
+}
+
+fn shell_tool_definition() -> serde_json::Value {
+    serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": "shell",
+            "description": "Run an allow-listed command on the user's machine and return its output.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "Program and arguments" }
+                },
+                "required": ["command"]
+            }
+        }
+    })
+}
+
+/// Names the cache entry for an exact message context.
+fn cache_key(messages: &[Message]) -> String {
+    use sha2::Digest;
+
+    let json = serde_json::to_vec(messages).expect("messages serialize");
+    sha2::Sha256::digest(&json)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Stores a successful reply under `key`, or, when the API could not be reached, answers
+/// with the reply stored for that same context, marked `_(cached)_`.
+async fn with_cache(dir: &Path, key: &str, completion: Result<Completion>, model: &str) -> Result<Completion> {
+    let path = dir.join(format!("{}.md", key));
+    match completion {
+        Ok(completion) => {
+            if let Err(e) = store_cached(dir, &path, &completion.content).await {
+                debug_log(&format!("error: could not cache reply: {}", e));
+            }
+            Ok(completion)
+        }
+        Err(e) if is_offline_error(&e) => match fs::read_to_string(&path).await {
+            Ok(cached) => {
+                debug_log("response: API unreachable, using the cached reply");
+                Ok(Completion {
+                    content: format!("{}\n\n_(cached)_", cached.trim_end()),
+                    tool_calls: Vec::new(),
+                    usage: None,
+                    model: model.to_string(),
+                    citations: Vec::new(),
+                })
+            }
+            Err(_) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+async fn store_cached(dir: &Path, path: &Path, content: &str) -> Result<()> {
+    fs::create_dir_all(dir).await?;
+    fs::write(path, content).await?;
+    Ok(())
+}
+
+/// Whether the request never got an answer: no connection, a timeout or a dropped one.
+fn is_offline_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<ChatError>().is_some_and(ChatError::is_offline))
+}
+
+/// Writes the outgoing messages to a temp file as JSON, opens it in `$EDITOR` (`vi` if
+/// unset) and returns what was saved. Quitting the editor with an error, or emptying the
+/// file, cancels the send.
+async fn edit_messages(messages: &[Message]) -> Result<Vec<Message>> {
+    // A fresh file only we can read, so no one else can swap in a request or read the context
+    let file = tempfile::Builder::new().prefix("chat-md-request-").suffix(".json").tempfile()?;
+    let path = file.path().to_path_buf();
+    fs::write(&path, serde_json::to_string_pretty(messages)?).await?;
+
+    let editor = env_string("EDITOR").unwrap_or_else(|| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().context("EDITOR is empty")?;
+    debug_log(&format!("call: editing request in {}", editor));
+    let status = subprocess(program)
+        .args(words)
+        .arg(&path)
+        .status()
+        .await
+        .with_context(|| format!("Failed to run {}", editor));
+    let edited = fs::read_to_string(&path).await;
+    drop(file);
+
+    let status = status?;
+    if !status.success() {
+        anyhow::bail!("Send cancelled: {} exited with {}", editor, status);
+    }
+    let edited = edited?;
+    if edited.trim().is_empty() {
+        anyhow::bail!("Send cancelled: the request file was emptied");
+    }
+    serde_json::from_str(&edited).context("Edited request is not a valid JSON message array")
+}
+
+/// What happened to the file while a reply streamed into it.
+enum StreamEdits {
+    /// Text typed below the reply, possibly none.
+    Typed(String),
+    /// Edited elsewhere; the file as it was when that was noticed.
+    Edited(String),
+}
+
+/// Streams a reply, rewriting `output` with `render` of the text so far at most every
+/// `STREAM_WRITE_INTERVAL`; the caller writes the finished reply. The file is read again
+/// before each write: text typed below the reply in the meantime is kept after it and
+/// returned, so the caller can keep it too. Any other edit stops the partial writes, rather
+/// than overwriting it, and is returned as the edited file instead.
+async fn stream_into_file(
+    api_client: &ApiClient,
+    config: &Config,
+    messages: Vec<Message>,
+    output: &Path,
+    render: impl Fn(&str) -> String,
+) -> Result<(Completion, StreamEdits)> {
+    let (sender, mut receiver) = tokio::sync::watch::channel(String::new());
+    let call = stream_with_fallback(api_client, config, messages, &sender);
+    tokio::pin!(call);
+    let mut last_write = Instant::now();
+    let mut written = read_chat_file(output, config.newest_first).await.unwrap_or_default();
+    let mut typed = String::new();
+    let mut edited = None;
+    loop {
+        tokio::select! {
+            result = &mut call => {
+                let completion = result?;
+                if let Some(edited) = edited {
+                    return Ok((completion, StreamEdits::Edited(edited)));
+                }
+                // Anything typed since the last partial write
+                let current = read_chat_file(output, config.newest_first).await.unwrap_or_default();
+                let edits = match current.strip_prefix(written.as_str()) {
+                    Some(added) => StreamEdits::Typed(typed + added),
+                    None => StreamEdits::Edited(current),
+                };
+                return Ok((completion, edits));
+            }
+            Ok(()) = receiver.changed() => {
+                let partial = receiver.borrow_and_update().clone();
+                if edited.is_some() || last_write.elapsed() < STREAM_WRITE_INTERVAL {
+                    continue;
+                }
+                let current = read_chat_file(output, config.newest_first).await.unwrap_or_default();
+                match current.strip_prefix(written.as_str()) {
+                    Some(added) => typed.push_str(added),
+                    None => {
+                        debug_log("skip: chat file edited while streaming, holding the reply until it is done");
+                        edited = Some(current);
+                        continue;
+                    }
+                }
+                written = format!("{}{}", render(&partial), typed);
+                write_chat_file(output, &written, config.newest_first).await?;
+                last_write = Instant::now();
+            }
+        }
+    }
+}
+
+/// Calls the model, running any `shell` tool calls it makes and feeding the output back until
+/// it answers in plain text.
+async fn complete_with_tools(
+    api_client: &ApiClient,
+    config: &Config,
+    mut messages: Vec<Message>,
+    search: bool,
+) -> Result<Completion> {
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let completion = call_with_fallback(api_client, config, messages.clone(), search).await?;
+        if completion.tool_calls.is_empty() || !api_client.provider.shell_tool {
+            return Ok(completion);
+        }
+
+        messages.push(Message {
+            tool_calls: completion.tool_calls.clone(),
+            ..Message::new(Role::Assistant, completion.content)
+        });
+        for call in completion.tool_calls {
+            let output = match run_tool_call(&call, &config.tool_allowlist).await {
+                Ok(output) => output,
+                Err(e) => format!("error: {}", e),
+            };
+            messages.push(Message {
+                tool_call_id: Some(call.id),
+                ..Message::new(Role::Tool, output)
+            });
+        }
+    }
+    anyhow::bail!("Model kept requesting tools after {} rounds", MAX_TOOL_ROUNDS)
+}
+
+/// Turns a reply's `[n]` citation markers into footnote references and lists the sources as
+/// footnotes below it. Markers inside code blocks and `[n](...)` links are left alone.
+fn with_footnotes(reply: &str, citations: &[Citation]) -> String {
+    static MARKER: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let marker = MARKER.get_or_init(|| regex::Regex::new(r"\[(\d+)\](\(?)").expect("valid citation pattern"));
+
+    let mut lines = Vec::new();
+    let mut open_fence: Option<(char, usize)> = None;
+    for line in reply.trim_end().lines() {
+        let trimmed = line.trim();
+        match open_fence {
+            Some(open) => {
+                if closes_fence(trimmed, open) {
+                    open_fence = None;
+                }
+                lines.push(line.to_string());
+            }
+            None => {
+                open_fence = fence_marker(trimmed);
+                let cited = marker.replace_all(line, |caps: &regex::Captures| {
+                    let number: usize = caps[1].parse().unwrap_or(0);
+                    if caps[2].is_empty() && (1..=citations.len()).contains(&number) {
+                        format!("[^{}]", number)
+                    } else {
+                        caps[0].to_string()
+                    }
+                });
+                lines.push(cited.into_owned());
+            }
+        }
+    }
+
+    lines.push(String::new());
+    for (number, citation) in citations.iter().enumerate() {
+        let source = match citation.title.as_deref().map(str::trim).filter(|title| !title.is_empty()) {
+            Some(title) => format!("[{}]({})", title.replace(['[', ']'], ""), citation.url),
+            None => format!("<{}>", citation.url),
+        };
+        lines.push(format!("[^{}]: {}", number + 1, source));
+    }
+    lines.join("\n")
+}
+
+/// Runs one `shell` tool call if its program is allow-listed. The command is split on
+/// whitespace and run directly, never through a shell, so pipes and globbing don't apply.
+async fn run_tool_call(call: &ToolCall, allowlist: &[String]) -> Result<String> {
+    #[derive(Deserialize)]
+    struct ShellArgs {
+        command: String,
+    }
+
+    if call.function.name != "shell" {
+        anyhow::bail!("unknown tool {}", call.function.name);
+    }
+    let args: ShellArgs = serde_json::from_str(&call.function.arguments)
+        .context("invalid shell tool arguments")?;
+    let words: Vec<&str> = args.command.split_whitespace().collect();
+    let (program, words) = words.split_first().context("empty command")?;
+    if !allowlist.iter().any(|allowed| is_allowed(allowed, program, words)) {
+        debug_log(&format!("skip: tool command not allow-listed: {}", args.command));
+        anyhow::bail!("{} is not on the allow-list", args.command);
+    }
+
+    debug_log(&format!("call: running tool command: {}", args.command));
+    let child = subprocess(program)
+        .args(words)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(TOOL_TIMEOUT, child)
+        .await
+        .with_context(|| format!("timed out after {}s", TOOL_TIMEOUT.as_secs()))??;
+
+    Ok(format!(
+        "{}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        truncate_output(&output.stdout, TOOL_OUTPUT_LIMIT),
+        truncate_output(&output.stderr, TOOL_OUTPUT_LIMIT)
+    ))
+}
+
+/// Whether an allow-list entry permits `program` with `args`. A bare program allows any
+/// arguments; an entry with arguments, such as `git status`, only allows commands starting
+/// with exactly those words.
+fn is_allowed(entry: &str, program: &str, args: &[&str]) -> bool {
+    let mut entry = entry.split_whitespace();
+    entry.next() == Some(program) && entry.enumerate().all(|(i, word)| args.get(i) == Some(&word))
+}
+
+fn truncate_output(bytes: &[u8], limit: usize) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    match text.char_indices().nth(limit) {
+        Some((end, _)) => format!("{}\n[truncated]", &text[..end]),
+        None => text.into_owned(),
+    }
+}
+
+/// Writes `input` to a child's stdin while collecting its output, both within
+/// `TOOL_TIMEOUT`, so a child that prints before it has read everything can't fill the pipe
+/// and stall the write. The write's result comes back separately, since a child that exits
+/// without reading its input is better reported by its status.
+async fn feed_child(
+    mut child: tokio::process::Child,
+    input: Vec<u8>,
+) -> Result<(std::io::Result<std::process::Output>, std::io::Result<()>), tokio::time::error::Elapsed> {
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write = async move {
+        let written = stdin.write_all(&input).await;
+        drop(stdin);
+        written
+    };
+    tokio::time::timeout(TOOL_TIMEOUT, async { tokio::join!(child.wait_with_output(), write) }).await
+}
+
+/// Pipes `input` as JSON through a transform command (split on whitespace like `$EDITOR`)
+/// and parses what it prints. Used to speak to providers whose API shape differs from ours.
+async fn run_transform(command: &str, input: serde_json::Value) -> Result<serde_json::Value> {
+    let mut words = command.split_whitespace();
+    let program = words.next().context("Transform command is empty")?;
+    let child = subprocess(program)
+        .args(words)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", command))?;
+
+    // A command that exits without reading its input is reported by its status below
+    let (output, written) = feed_child(child, serde_json::to_vec(&input)?)
+        .await
+        .with_context(|| format!("{} timed out after {}s", command, TOOL_TIMEOUT.as_secs()))?;
+    let output = output?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    written.with_context(|| format!("Failed to write to {}", command))?;
+    serde_json::from_slice(&output.stdout).with_context(|| format!("{} did not print valid JSON", command))
+}
+
+/// `--migrate-separator`: rewrites every legacy separator line in `path` to the current
+/// separator, backing the file up first.
+async fn migrate_separator(path: &Path, newest_first: bool) -> Result<()> {
+    let content = read_chat_file(path, newest_first)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if LEGACY_SEPARATORS.get().is_none_or(Vec::is_empty) {
+        anyhow::bail!("Set CHAT_LEGACY_SEPARATORS to the separator(s) being replaced");
+    }
+
+    let (front_matter, _) = split_front_matter(&content);
+    let turns = split_turns(&content);
+    let primary = separator().trim();
+    let replaced = turns
+        .windows(2)
+        .filter(|pair| {
+            let end = pair[0].start + pair[0].text.len();
+            content[end..pair[1].start].trim() != primary
+        })
+        .count();
+    if replaced == 0 {
+        println!("{}: nothing to migrate", path.display());
+        return Ok(());
+    }
+
+    let texts: Vec<&str> = turns.iter().map(|turn| turn.text).collect();
+    let migrated = format!("{}{}", front_matter, texts.join(separator().trim_start()));
+    let backup = backup_chat_file(path, &content, newest_first).await?;
+    write_chat_file(path, &migrated, newest_first).await?;
+    println!(
+        "{}: replaced {} separator(s) with {:?}, backup at {}",
+        path.display(),
+        replaced,
+        primary,
+        backup.display()
+    );
+    Ok(())
+}
+
+/// `--once`: replies to the conversation piped in on stdin, without touching any file.
+async fn run_once(api_client: &ApiClient, config: &Config, chat_context: &mut ChatContext) -> Result<()> {
+    let mut content = String::new();
+    tokio::io::stdin().read_to_string(&mut content).await?;
+    let content = from_file_order(content, config.newest_first);
+    let content = content.trim_end();
+
+    let history = chat_context.history(content, content.len(), config);
+    let message = split_turns(content)
+        .last()
+        .filter(|turn| turn.role == Role::User)
+        .map(|turn| message_text(turn.text, config.user_wrapper.as_ref()))
+        .filter(|message| !message.is_empty())
+        .context("stdin does not end with a user message")?;
+
+    let message = expand_snippets(&message, &chat_dir(&chat_context.path).join(&config.snippet_dir)).await?;
+
+    if let Some(header) = &config.conversation_header {
+        api_client.set_conversation(header, chat_context.conversation_id().await);
+    }
+    chat_context.load_memory().await;
+    let mut messages = chat_context.assemble(history, api_client.instruction_role());
+    if let Some(addendum) = config.clarify_addendum(&message) {
+        messages.push(Message::new(api_client.instruction_role(), addendum));
+    }
+    let outgoing = expand_attachments(&message, chat_dir(&chat_context.path), config).await?;
+    messages.push(chat_context.user_message(outgoing));
+    debug_log(&format!("call: sending request with {} messages", messages.len()));
+
+    let completion = complete_with_tools(api_client, config, messages, false).await?;
+    println!("{}", completion.content.trim());
+    Ok(())
+}
+
+/// Sends a one-word ping to the configured provider and reports the model and latency,
+/// so a setup can be checked without touching the chat file.
+async fn run_check(api_client: &ApiClient) -> Result<()> {
+    let started = Instant::now();
+    let messages = vec![Message::new(Role::User, "Reply with the word pong.")];
+    match api_client.call_api(messages, false).await {
+        Ok(completion) => {
+            println!(
+                "ok: {} answered in {} ms",
+                completion.model,
+                started.elapsed().as_millis()
+            );
+            Ok(())
+        }
+        Err(e) => anyhow::bail!(
+            "check failed after {} ms against {} ({}): {}",
+            started.elapsed().as_millis(),
+            api_client.endpoint(),
+            api_client.provider.model,
+            e
+        ),
+    }
+}
+
+/// Replaces `/diff` and `/diff --cached` lines in an outgoing message with the current
+/// `git diff`, and `@include <path>` lines with that file (relative to `dir`). Only the
+/// sent copy is expanded; chat.md keeps the short token.
+async fn expand_attachments(message: &str, dir: &Path, config: &Config) -> Result<String> {
+    if !message.lines().any(|line| diff_args(line).is_some() || include_path(line).is_some()) {
+        return Ok(message.to_string());
+    }
+
+    let mut expanded = Vec::new();
+    for line in message.lines() {
+        if let Some(path) = include_path(line) {
+            let resolved = path_within(dir, path).await?;
+            let included = include_file(&resolved, path, config.include_budget, config.include_line_numbers).await?;
+            expanded.push(included);
+            continue;
+        }
+        let Some(args) = diff_args(line) else {
+            expanded.push(line.to_string());
+            continue;
+        };
+
+        let output = tokio::time::timeout(
+            TOOL_TIMEOUT,
+            subprocess("git")
+                .arg("diff")
+                .args(args)
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .context("git diff timed out")?
+        .context("Failed to run git diff")?;
+        if !output.status.success() {
+            anyhow::bail!("git diff failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        let label = std::iter::once("git diff").chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+        let diff = truncate_output(&output.stdout, DIFF_LIMIT);
+        debug_log(&format!("add: inlining {} ({} bytes)", label, diff.len()));
+        if diff.trim().is_empty() {
+            expanded.push(format!("({}: no changes)", label));
+        } else {
+            expanded.push(format!("Output of `{}`:\n{}", label, fenced("diff", &diff)));
+        }
+    }
+    Ok(expanded.join("\n"))
+}
+
+/// Replaces each `/snippet <name>` line with the contents of `<name>.md` (or `<name>` as
+/// given) in `dir`. Runs before `expand_attachments`, so a snippet may hold an `@include`.
+async fn expand_snippets(text: &str, dir: &Path) -> Result<String> {
+    if !text.lines().any(|line| snippet_name(line).is_some()) {
+        return Ok(text.to_string());
+    }
+
+    let mut expanded = Vec::new();
+    for line in text.lines() {
+        let Some(name) = snippet_name(line) else {
+            expanded.push(line.to_string());
+            continue;
+        };
+        if !is_plain_name(name) {
+            anyhow::bail!("Snippet names can't contain a path: {}", name);
+        }
+        let path = [dir.join(format!("{}.md", name)), dir.join(name)]
+            .into_iter()
+            .find(|path| path.is_file())
+            .with_context(|| format!("No such snippet: {} in {}", name, dir.display()))?;
+        let snippet = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read snippet {}", path.display()))?;
+        debug_log(&format!("add: expanding snippet {}", name));
+        expanded.push(snippet.trim_end().to_string());
+    }
+    Ok(expanded.join("\n"))
+}
+
+fn snippet_name(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("/snippet ")
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+fn include_path(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("@include ")
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+}
+
+/// Resolves `relative` against `dir`, refusing anything that ends up outside it: absolute
+/// paths, `..` and symlinks that point elsewhere. Keeps an `@include` from sending files such
+/// as `~/.ssh` keys or a parent directory's `.env` to the API.
+async fn path_within(dir: &Path, relative: &str) -> Result<PathBuf> {
+    let root = fs::canonicalize(dir).await?;
+    let path = fs::canonicalize(dir.join(relative))
+        .await
+        .with_context(|| format!("Failed to include {}", relative))?;
+    if !path.starts_with(&root) {
+        anyhow::bail!("Can't include {}: it is outside {}", relative, dir.display());
+    }
+    Ok(path)
+}
+
+/// Reads an `@include`d file for the outgoing message. A file whose estimated token cost is
+/// over `budget` keeps only its first and last lines, with a note of what was left out.
+/// With `line_numbers` each line is prefixed with its number in the file, so the kept tail
+/// is still numbered as in an editor.
+async fn include_file(path: &Path, label: &str, budget: usize, line_numbers: bool) -> Result<String> {
+    let text = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to include {}", path.display()))?;
+    let lines: Vec<&str> = text.lines().collect();
+    let width = lines.len().to_string().len();
+    // The `NNN | ` in front of each numbered line
+    let prefix = if line_numbers { width + 3 } else { 0 };
+    let show = |first: usize, lines: &[&str]| {
+        if !line_numbers {
+            return lines.join("\n");
+        }
+        let numbered = lines.iter().enumerate().map(|(i, line)| format!("{:>width$} | {}", first + i, line));
+        numbered.collect::<Vec<_>>().join("\n")
+    };
+    let whole = if line_numbers { show(1, &lines) } else { text.clone() };
+    let tokens = estimate_tokens(&whole);
+    let body = if tokens <= budget {
+        debug_log(&format!("add: including {} (~{} tokens)", label, tokens));
+        whole
+    } else {
+        let half = budget * CHARS_PER_TOKEN / 2;
+        let head = lines_within(lines.iter().copied(), prefix, half);
+        let tail = lines_within(lines[head..].iter().rev().copied(), prefix, half);
+        let omitted = &lines[head..lines.len() - tail];
+        debug_log(&format!(
+            "trim: including {} truncated to its first {} and last {} lines (~{} tokens, budget {})",
+            label, head, tail, tokens, budget
+        ));
+        format!(
+            "{}\n[... {} lines (~{} tokens) omitted ...]\n{}",
+            show(1, &lines[..head]),
+            omitted.len(),
+            estimate_tokens(&omitted.join("\n")),
+            show(lines.len() - tail + 1, &lines[lines.len() - tail..])
+        )
+    };
+    let info = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    Ok(format!("Contents of `{}`:\n{}", label, fenced(info, &body)))
+}
+
+/// How many of `lines` fit, in order, within `chars` characters, each with `prefix` more
+/// in front of it.
+fn lines_within<'a>(lines: impl Iterator<Item = &'a str>, prefix: usize, chars: usize) -> usize {
+    let mut used = 0;
+    lines
+        .take_while(|line| {
+            used += prefix + line.chars().count() + 1;
+            used <= chars
+        })
+        .count()
+}
+
+/// A rough token count; close enough for budgeting without a tokenizer.
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+fn diff_args(line: &str) -> Option<&'static [&'static str]> {
+    match line.trim() {
+        "/diff" => Some(&[]),
+        "/diff --cached" => Some(&["--cached"]),
+        _ => None,
+    }
+}
+
+/// Replaces each `<!-- ask: question -->` in a user turn, outside code blocks and scratch
+/// regions, with the model's answer, asked on its own without the conversation, and lists
+/// each question with its answer. `None` when there is nothing to ask.
+async fn answer_inline_asks(content: &str, api_client: &ApiClient) -> Result<Option<(String, Vec<(String, String)>)>> {
+    static ASK: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let ask = ASK.get_or_init(|| regex::Regex::new(r"(?s)<!--\s*ask:(.*?)-->").expect("valid ask pattern"));
+
+    // A reply that quotes the marker must not make the monitor ask it
+    let user_turns: Vec<std::ops::Range<usize>> = split_turns(content)
+        .into_iter()
+        .filter(|turn| turn.role == Role::User)
+        .map(|turn| turn.start..turn.start + turn.text.len())
+        .collect();
+    let mut fences = fenced_ranges(content);
+    fences.extend(scratch_ranges(content));
+    let asks: Vec<(std::ops::Range<usize>, &str)> = ask
+        .captures_iter(content)
+        .filter_map(|caps| {
+            let whole = caps.get(0)?;
+            let in_user_turn = user_turns.iter().any(|turn| turn.contains(&whole.start()));
+            let inside_code = fences.iter().any(|skipped| skipped.contains(&whole.start()));
+            let question = caps.get(1)?.as_str().trim();
+            (in_user_turn && !inside_code && !question.is_empty()).then_some((whole.range(), question))
+        })
+        .collect();
+    if asks.is_empty() {
+        return Ok(None);
+    }
+
+    let mut answered = content.to_string();
+    let mut asked = Vec::new();
+    // Back to front, so earlier offsets stay valid as answers are spliced in
+    for (range, question) in asks.into_iter().rev() {
+        debug_log(&format!("call: answering inline ask {:?}", question));
+        let answer = api_client
+            .call_model(&api_client.provider.model, vec![Message::new(Role::User, question)], false)
+            .await?;
+        answered.replace_range(range, answer.content.trim());
+        asked.insert(0, (question.to_string(), answer.content.trim().to_string()));
+    }
+    Ok(Some((answered, asked)))
+}
+
+/// Byte ranges of fenced code blocks, fences included; an unclosed fence runs to the end.
+fn fenced_ranges(content: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut open_fence: Option<((char, usize), usize)> = None;
+    let mut offset = 0;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        match open_fence {
+            Some((open, start)) => {
+                if closes_fence(trimmed, open) {
+                    ranges.push(start..offset + line.len());
+                    open_fence = None;
+                }
+            }
+            None => open_fence = fence_marker(trimmed).map(|marker| (marker, offset)),
+        }
+        offset += line.len();
+    }
+    if let Some((_, start)) = open_fence {
+        ranges.push(start..content.len());
+    }
+    ranges
+}
+
+/// The `<!-- send -->` marker in the open turn, outside code blocks and scratch regions.
+fn send_marker(content: &str) -> Option<std::ops::Range<usize>> {
+    static SEND: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let send = SEND.get_or_init(|| regex::Regex::new(r"<!--\s*send\s*-->").expect("valid send pattern"));
+
+    let open_turn = split_turns(content).last().map_or(0, |turn| turn.start);
+    let mut skipped = fenced_ranges(content);
+    skipped.extend(scratch_ranges(content));
+    send.find_iter(&content[open_turn..])
+        .map(|found| open_turn + found.start()..open_turn + found.end())
+        .find(|marker| !skipped.iter().any(|range| range.contains(&marker.start)))
+}
+
+/// `content` with trailing spaces and repeated blank lines removed, for telling cosmetic
+/// edits from real ones. Whether it ends with the send trigger still counts.
+fn cosmetic_form(content: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in content.trim_end().lines().map(str::trim_end) {
+        if !(line.is_empty() && lines.last().is_some_and(|last| last.is_empty())) {
+            lines.push(line);
+        }
+    }
+    let trigger = if ends_with_blank_line(content) { DOUBLE_NEWLINE } else { "" };
+    format!("{}{}", lines.join("\n"), trigger)
+}
+
+/// The part of `content` that differs from `previous`, widened by a few bytes so a marker
+/// the edit completed is included whole. Compared a block at a time, so a keystroke in a
+/// large file costs a memcmp rather than a walk over every character.
+fn edited_region<'a>(content: &'a str, previous: &str) -> &'a str {
+    const BLOCK: usize = 4096;
+    const MARGIN: usize = 16;
+    let (a, b) = (content.as_bytes(), previous.as_bytes());
+    let shortest = a.len().min(b.len());
+
+    let mut prefix = 0;
+    while prefix + BLOCK <= shortest && a[prefix..prefix + BLOCK] == b[prefix..prefix + BLOCK] {
+        prefix += BLOCK;
+    }
+    while prefix < shortest && a[prefix] == b[prefix] {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    let rest = shortest - prefix;
+    while suffix + BLOCK <= rest && a[a.len() - suffix - BLOCK..a.len() - suffix] == b[b.len() - suffix - BLOCK..b.len() - suffix] {
+        suffix += BLOCK;
+    }
+    while suffix < rest && a[a.len() - suffix - 1] == b[b.len() - suffix - 1] {
+        suffix += 1;
+    }
+
+    let mut start = prefix.saturating_sub(MARGIN);
+    let mut end = (a.len() - suffix + MARGIN).min(a.len());
+    while !content.is_char_boundary(start) {
+        start -= 1;
+    }
+    while !content.is_char_boundary(end) {
+        end += 1;
+    }
+    &content[start..end]
+}
+
+/// Splits `content` at its send marker into the part to send, ending as if the user pressed
+/// Enter twice, and the draft after the marker, which stays unsent; `None` without a marker.
+fn split_at_send_marker(content: &str) -> Option<(String, String)> {
+    let marker = send_marker(content)?;
+    let sent = format!("{}{}", content[..marker.start].trim_end(), DOUBLE_NEWLINE);
+    let draft = content[marker.end..].trim_start_matches([' ', '\t', '\n']).to_string();
+    Some((sent, draft))
+}
+
+/// Whether the user pressed Enter twice, tolerating stray spaces or extra newlines that some
+/// editors leave at the end of the file.
+fn ends_with_blank_line(content: &str) -> bool {
+    let trailing = &content[content.trim_end().len()..];
+    trailing.matches('\n').count() >= 2
+}
+
+async fn process_new_messages(
+    content: String,
+    last_content: Arc<Mutex<String>>,
+    api_client: Arc<ApiClient>,
+    chat_context: Arc<Mutex<ChatContext>>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let received = SystemTime::now();
+    let mut last_content = last_content.lock().await;
+    let path = {
+        let mut chat_context = chat_context.lock().await;
+        if let Some(header) = &config.conversation_header {
+            api_client.set_conversation(header, chat_context.conversation_id().await);
+        }
+        if config.save_draft {
+            chat_context.update_draft(&content, config.reply_file).await;
+        }
+        chat_context.path.clone()
+    };
+    let input = content;
+    // Under CHAT_REPLY_FILE the input is never written: anything the tool adds goes into the
+    // reply file, as the open turn's reply
+    let replies = if config.reply_file {
+        read_chat_file(&reply_path(&path), config.newest_first).await.unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let content = if config.reply_file { merge_replies(&input, &replies) } else { input.clone() };
+    let merged = content.clone();
+    let output = config.output_path(&path);
+
+    let pause_file = chat_dir(&path).join(&config.pause_file);
+    if fs::try_exists(&pause_file).await? {
+        debug_log("skip: paused");
+        *last_content = content;
+        return Ok(());
+    }
+
+    if content == *last_content {
+        debug_log("unchanged: no new content");
+        return Ok(());
+    }
+
+    // Typing that doesn't reach the send trigger can only matter by completing an ask or
+    // send marker, so skip the whole-file scans below; they cost too much on every keystroke
+    // in a file with a huge pasted line
+    let edited = edited_region(&content, &last_content);
+    if !ends_with_blank_line(&content) && !edited.contains("<!--") && !edited.contains("-->") {
+        debug_log("skip: waiting for double enter");
+        *last_content = content;
+        return Ok(());
+    }
+
+    if strip_scratch(&content) == strip_scratch(&last_content) {
+        debug_log("unchanged: only the scratch region was edited");
+        *last_content = content;
+        return Ok(());
+    }
+
+    if config.ignore_whitespace_edits && cosmetic_form(&content) == cosmetic_form(&last_content) {
+        debug_log("unchanged: only whitespace was edited");
+        *last_content = content;
+        return Ok(());
+    }
+
+    if is_ignored(&path).await {
+        debug_log(&format!("skip: {} matches {}", file_name(&path), IGNORE_FILE));
+        *last_content = content;
+        return Ok(());
+    }
+
+    // With a reply file the questions are answered once the message is sent
+    let answered = if config.reply_file { None } else { answer_inline_asks(&input, &api_client).await? };
+    if let Some((answered, _)) = answered {
+        backup_chat_file(&path, &input, config.newest_first).await?;
+        write_chat_file(&path, &answered, config.newest_first).await?;
+        if config.sign {
+            let mut chat_context = chat_context.lock().await;
+            check_signature(&path, &input, &mut chat_context.altered_turn).await?;
+            resign(&path, &answered, chat_context.altered_turn).await?;
+        }
+        *last_content = read_conversation(&path, config.reply_file, config.newest_first).await?;
+        return Ok(());
+    }
+
+    // A send marker sends the text above it right away and keeps the rest as a draft
+    let (file_content, content, draft) = match split_at_send_marker(&content) {
+        Some((sent, draft)) => {
+            debug_log(&format!("detect: send marker, keeping {} chars of draft", draft.chars().count()));
+            (content, sent, draft)
+        }
+        None if !ends_with_blank_line(&content) => {
+            debug_log("skip: waiting for double enter");
+            *last_content = content;
+            return Ok(());
+        }
+        None => (content.clone(), content, String::new()),
+    };
+    let content = match collapse_separators(&content) {
+        Some((collapsed, count)) if config.collapse_separators => {
+            debug_log(&format!("parse: collapsed {} doubled separator(s)", count));
+            collapsed
+        }
+        _ => content,
+    };
+    let content = match config.auto_separator {
+        Some(gap) => split_long_gaps(&content, gap).unwrap_or(content),
+        None => content,
+    };
+
+    let mut chat_context = chat_context.lock().await;
+    if config.sign {
+        let chat_path = chat_context.path.clone();
+        check_signature(&chat_path, &merged, &mut chat_context.altered_turn).await?;
+    }
+
+    // Without a separator after the reply, the user's new text has to be split off it; a
+    // last turn that still reads as the assistant's has nothing new
+    let content = match config.reply_ending {
+        ReplyEnding::Separator => content,
+        _ => split_after_reply(&content).unwrap_or(content),
+    };
+    let cursor_pos = content.trim_end().len();
+    let reply_is_open = config.reply_ending != ReplyEnding::Separator
+        && split_turns(&content).last().is_some_and(|turn| turn.role == Role::Assistant);
+
+    if reply_is_open || chat_context.is_last_message_from_ai(&content, cursor_pos) {
+        debug_log("skip: last message was from AI");
+        *last_content = file_content;
+        return Ok(());
+    }
+
+    let message_content = chat_context.extract_new_message(&content, cursor_pos, &config);
+    if message_content.is_empty() {
+        debug_log("skip: empty message");
+        *last_content = file_content;
+        return Ok(());
+    }
+    let parsed = SystemTime::now();
+
+    let history = chat_context.history(&content, cursor_pos, &config);
+    let first_turn = history.iter().all(|message| message.role == Role::System);
+    chat_context.load_memory().await;
+    let mut messages = chat_context.assemble(history, api_client.instruction_role());
+
+    if let Some(command) = Command::parse(&message_content) {
+        // The note goes into the file the command was typed in, even after a switch
+        let path = chat_context.path.clone();
+
+        // Replace the command text with its output so it never becomes part of a turn
+        let start = content.rfind(Command::line(&message_content)).unwrap_or(cursor_pos);
+        let content_before = &content[..start];
+
+        let outcome = run_command(command, content_before, &messages, &mut chat_context, &api_client, &config)
+            .await
+            .unwrap_or_else(|e| {
+                debug_log(&format!("error: {:#}", e));
+                CommandOutcome::Note(format!("error: {:#}", e))
+            });
+
+        let rewritten = matches!(outcome, CommandOutcome::Restore(_)) && !config.reply_file;
+        let block = match &outcome {
+            CommandOutcome::Note(text) => note_block(text),
+            CommandOutcome::Scratch(text) => scratch_block(text),
+            CommandOutcome::Restore(_) => {
+                note_block("error: this command rewrites the conversation, which CHAT_REPLY_FILE leaves alone")
+            }
+        };
+        let updated = match outcome {
+            CommandOutcome::Restore(restored) if !config.reply_file => restored,
+            _ if config.reply_file => append_reply(&replies, &block),
+            _ => format!("{}{}{}{}", content_before, block, DOUBLE_NEWLINE, draft),
+        };
+        write_chat_file(&output, &updated, config.newest_first).await?;
+        if config.save_draft {
+            chat_context.clear_draft(&path).await;
+        }
+        if config.sign && rewritten {
+            sign_turns(&path, &updated).await?;
+            debug_log(&format!("write: re-signed {} after the rewrite", file_name(&path)));
+        }
+
+        *last_content = read_conversation(&chat_context.path, config.reply_file, config.newest_first).await?;
+        return Ok(());
+    }
+
+    let message_content = match &config.trigger {
+        Some(trigger) => match strip_trigger(&message_content, trigger) {
+            Some(addressed) if !addressed.is_empty() => addressed,
+            _ => {
+                debug_log(&format!("skip: message not addressed with {}, keeping it as a note", trigger));
+                *last_content = file_content;
+                return Ok(());
+            }
+        },
+        None => message_content,
+    };
+
+    let snippet_dir = chat_dir(&chat_context.path).join(&config.snippet_dir);
+    let message_content = expand_snippets(&message_content, &snippet_dir).await?;
+    let (content, cursor_pos) = if config.write_snippets {
+        let open_turn = split_turns(&content[..cursor_pos]).last().map_or(0, |turn| turn.start);
+        let expanded = expand_snippets(&content[open_turn..], &snippet_dir).await?;
+        let content = format!("{}{}{}", &content[..open_turn], expanded.trim_end(), DOUBLE_NEWLINE);
+        let cursor_pos = content.trim_end().len();
+        (content, cursor_pos)
+    } else {
+        (content, cursor_pos)
+    };
+
+    if api_client.is_paused() {
+        debug_log("skip: paused after too many API failures; type /resume to send again");
+        *last_content = file_content;
+        return Ok(());
+    }
+
+    // Commands are short by nature; only chat messages need to clear the minimum
+    if message_content.chars().count() < config.min_chars
+        || message_content.split_whitespace().count() < config.min_words
+    {
+        debug_log("skip: message too short");
+        *last_content = file_content;
+        return Ok(());
+    }
+
+    if let Some(max_turns) = config.max_turns {
+        let turns = turn_count(&content[..cursor_pos]).saturating_sub(1);
+        if turns >= max_turns {
+            debug_log(&format!("skip: turn limit of {} reached", max_turns));
+            let notice = note_block(&format!(
+                "turn limit reached ({} of {}): start a new chat with /fork at=N or /switch",
+                turns, max_turns
+            ));
+            // Only write the notice once; later edits just keep skipping
+            if !file_content.contains(&notice) {
+                let updated = if config.reply_file {
+                    append_reply(&replies, &notice)
+                } else {
+                    format!("{}\n\n{}\n", file_content.trim_end(), notice)
+                };
+                write_chat_file(&output, &updated, config.newest_first).await?;
+            }
+            *last_content = read_conversation(&chat_context.path, config.reply_file, config.newest_first).await?;
+            return Ok(());
+        }
+    }
+
+    if let Some(addendum) = config.clarify_addendum(&message_content) {
+        debug_log("add: asking for clarifying questions on a short message");
+        messages.push(Message::new(api_client.instruction_role(), addendum));
+    }
+    // The input file keeps its questions; they are answered in what is sent and in a note
+    // above the reply
+    let mut answers = None;
+    let answered = if config.reply_file { answer_inline_asks(&message_content, &api_client).await? } else { None };
+    let message_content = match answered {
+        Some((answered, asked)) => {
+            let asked: Vec<String> =
+                asked.iter().map(|(question, answer)| format!("ask: {}\n{}", question, answer)).collect();
+            answers = Some(note_block(&asked.join("\n\n")));
+            answered
+        }
+        None => message_content,
+    };
+    let outgoing = expand_attachments(&message_content, chat_dir(&chat_context.path), &config).await?;
+    messages.push(chat_context.user_message(outgoing));
+    let assembled = SystemTime::now();
+    let message_count = messages.len();
+
+    debug_log(&format!("parse: sending message: {:?}", message_content));
+
+    // Call API
+    debug_log(&format!("call: sending request with {} messages", messages.len()));
+    let context_chars: usize = messages.iter().map(|message| message.content.chars().count()).sum();
+    let pending = pending_marker(&chat_context.path);
+    fs::write(&pending, content.trim_end().len().to_string()).await?;
+    if config.edit_before_send {
+        match edit_messages(&messages).await {
+            Ok(edited) => messages = edited,
+            Err(e) => {
+                let _ = fs::remove_file(&pending).await;
+                return Err(e);
+            }
+        }
+    }
+    let on_disk = if config.reply_file { &replies } else { &input };
+    set_status(&config, &output, "sending", Some(on_disk)).await;
+    let started = Instant::now();
+    let sent = SystemTime::now();
+    let cache_key = config.response_cache.as_ref().map(|_| cache_key(&messages));
+    // Citations only come with the whole response, so a searched turn isn't streamed
+    let search = std::mem::take(&mut chat_context.search);
+    let mut typed_while_streaming = String::new();
+    let mut edited_while_streaming = None;
+    // Read before the call: a streamed reply is in the reply file by the time it is done
+    let replies = if config.reply_file {
+        read_chat_file(&output, config.newest_first).await.unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let completion = if chat_context.stream && api_client.can_stream() && !search {
+        let render = |partial: &str| {
+            if config.reply_file {
+                append_reply(&replies, partial)
+            } else if draft.is_empty() {
+                append_turn(&content, partial, ReplyEnding::Nothing)
+            } else {
+                format!("{}{}", append_turn(&content, partial, ReplyEnding::Separator), draft)
+            }
+        };
+        with_spinner(stream_into_file(&api_client, &config, messages, &output, render)).await.map(
+            |(completion, edits)| {
+                match edits {
+                    StreamEdits::Typed(typed) => typed_while_streaming = typed,
+                    StreamEdits::Edited(edited) => edited_while_streaming = Some(edited),
+                }
+                completion
+            },
+        )
+    } else {
+        with_spinner(complete_with_tools(&api_client, &config, messages, search)).await
+    };
+    let completion = match (&config.response_cache, &cache_key) {
+        (Some(dir), Some(key)) => with_cache(dir, key, completion, &api_client.provider.model).await,
+        _ => completion,
+    };
+    let elapsed = started.elapsed();
+    let model: serde_json::Value = api_client.provider.model.as_str().into();
+    let mut trace = config.otlp_url.as_ref().map(|_| {
+        let mut trace = TurnTrace::new(received);
+        trace.phase("parse", received, parsed, &[("chat.message_chars", message_content.chars().count().into())], None);
+        trace.phase("assemble", parsed, assembled, &[("chat.messages", message_count.into())], None);
+        trace
+    });
+    if let Err(e) = &completion {
+        let _ = fs::remove_file(&pending).await;
+        if let Some(mut trace) = trace.take() {
+            let error = e.to_string();
+            let request_model = ("gen_ai.request.model", model.clone());
+            trace.phase("call_api", sent, SystemTime::now(), std::slice::from_ref(&request_model), Some(&error));
+            trace.export(&api_client.client, &config, &[request_model, ("chat.outcome", "error".into())], Some(&error));
+        }
+    }
+    let completion = completion?;
+    let tokens: Vec<(&str, serde_json::Value)> = vec![
+        ("gen_ai.request.model", model),
+        ("gen_ai.response.model", completion.model.clone().into()),
+        ("gen_ai.usage.input_tokens", completion.usage.as_ref().map_or(0, |usage| usage.prompt_tokens).into()),
+        ("gen_ai.usage.output_tokens", completion.usage.as_ref().map_or(0, |usage| usage.completion_tokens).into()),
+    ];
+    if let Some(trace) = &mut trace {
+        trace.phase("call_api", sent, sent + elapsed, &tokens, None);
+    }
+    let response = &completion.content;
+    set_status(&config, &output, "receiving", Some(on_disk)).await;
+
+    if let Some(delay) = config.reply_delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut reply = response.clone();
+    if !completion.citations.is_empty() {
+        debug_log(&format!("response: {} citation(s)", completion.citations.len()));
+        reply = with_footnotes(&reply, &completion.citations);
+    }
+    if chat_context.timing {
+        let tokens = completion
+            .usage
+            .as_ref()
+            .map(|usage| format!(", {} tokens", usage.completion_tokens))
+            .unwrap_or_default();
+        reply.push_str(&format!("\n\n_({:.1}s{})_", elapsed.as_secs_f64(), tokens));
+    }
+
+    if config.reply_metadata {
+        reply.push_str(&format!("\n\n{}", reply_metadata(&completion, &api_client.provider.extra_body)));
+    }
+
+    if config.check_patches {
+        let patches = diff_blocks(response);
+        let mut results = Vec::new();
+        for (i, patch) in patches.iter().enumerate() {
+            let result = check_patch(patch, chat_dir(&chat_context.path)).await;
+            debug_log(&format!("write: patch {} of {}: {}", i + 1, patches.len(), one_line(&result, PREVIEW_CHARS)));
+            results.push(match patches.len() {
+                1 => format!("patch: {}", result),
+                _ => format!("patch {}: {}", i + 1, result),
+            });
+        }
+        if !results.is_empty() {
+            reply.push_str(&format!("\n\n{}", note_block(&results.join("\n"))));
+        }
+    }
+
+    // Warn once when the context grows past the budget, and again only after it drops back
+    if let Some(limit) = config.context_warn_chars {
+        if context_chars <= limit {
+            chat_context.context_warned = false;
+        } else if !chat_context.context_warned {
+            chat_context.context_warned = true;
+            debug_log(&format!("skip: context is {} chars, over the {} budget", context_chars, limit));
+            reply.push_str(&format!(
+                "\n\n{}",
+                note_block(&format!(
+                    "context is large ({} chars, budget {}); consider /summarize or /fork",
+                    context_chars, limit
+                ))
+            ));
+        }
+    }
+
+    // Append response
+    debug_log("write: adding assistant response");
+    let reply = wrap_turn(config.assistant_wrapper.as_ref(), &reply);
+    let mut updated = if config.reply_file {
+        let reply = match &answers {
+            Some(answers) => format!("{}{}{}", answers, DOUBLE_NEWLINE, reply),
+            None => reply,
+        };
+        append_reply(&replies, &reply)
+    } else {
+        backup_chat_file(&chat_context.path, &file_content, config.newest_first).await?;
+        chat_context.redo_stack.clear();
+        // A draft kept below a send marker opens the next turn, so it always gets a separator
+        let ending = if draft.is_empty() { config.reply_ending } else { ReplyEnding::Separator };
+        let content = if config.normalize_user { normalize_last_turn(&content) } else { content };
+        let content = wrap_last_turn(&content, config.user_wrapper.as_ref());
+        let mut updated = append_turn(&content, &with_reply_end(&reply, ending), ending);
+        updated.push_str(&draft);
+        updated
+    };
+    updated.push_str(&typed_while_streaming);
+    // The reply is written from the file as sent; keep the edited one where /undo finds it
+    if let Some(edited) = edited_while_streaming.filter(|_| !config.reply_file) {
+        let path = backup_chat_file(&chat_context.path, &edited, config.newest_first).await?;
+        debug_log(&format!("write: kept the file as edited during the reply in {}", path.display()));
+    }
+
+    if config.auto_title && first_turn && front_matter_title(&updated).is_none() {
+        let model = config.title_model.as_deref().unwrap_or(&api_client.provider.model);
+        match generate_title(&api_client, model, &message_content).await {
+            Ok(title) => {
+                debug_log(&format!("write: titled conversation {:?}", title));
+                updated = with_title(&updated, &title);
+            }
+            Err(e) => debug_log(&format!("error: title generation failed: {:#}", e)),
+        }
+    }
+
+    // The reply's write is also the one that sets the status back to idle
+    let status = config.status_line.then(|| status_line("idle"));
+    write_chat_file_with_status(&output, &updated, config.newest_first, status.as_deref()).await?;
+    let checkable = !config.reply_file && config.reply_ending == ReplyEnding::Separator;
+    if let Some(mode) = config.verify_writes.filter(|_| checkable) {
+        verify_written(&chat_context.path, mode, config.newest_first).await?;
+    }
+    fs::remove_file(&pending).await?;
+    if config.save_draft {
+        let chat_path = chat_context.path.clone();
+        chat_context.clear_draft(&chat_path).await;
+        // What was kept below a send marker is the next turn's draft already
+        if !draft.trim().is_empty() {
+            chat_context.save_draft(draft.trim()).await;
+        }
+    }
+    METRICS.turns.fetch_add(1, Ordering::Relaxed);
+    if let Some(usage) = &completion.usage {
+        if let Err(e) = record_usage(&chat_context.path, &completion.model, usage).await {
+            debug_log(&format!("error: could not record usage: {}", e));
+        }
+    }
+
+    if let Some(copy_reply) = config.copy_reply {
+        match copy_reply.select(response) {
+            Some(text) => match copy_to_clipboard(text) {
+                Ok(()) => debug_log("write: copied reply to clipboard"),
+                Err(e) => debug_log(&format!("skip: clipboard unavailable: {}", e)),
+            },
+            None => debug_log("skip: reply has no code block to copy"),
+        }
+    }
+
+    if let Some(trace) = trace {
+        let mut attributes = tokens;
+        attributes.push(("chat.outcome", "ok".into()));
+        trace.export(&api_client.client, &config, &attributes, None);
+    }
+
+    if let Some(url) = &config.webhook_url {
+        let payload = WebhookPayload {
+            file: chat_context.path.display().to_string(),
+            user: message_content,
+            assistant: completion.content.trim().to_string(),
+            usage: completion.usage,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        tokio::spawn(deliver_webhook(api_client.client.clone(), url.clone(), payload));
+    }
+
+    *last_content = read_conversation(&chat_context.path, config.reply_file, config.newest_first).await?;
+    if config.sign {
+        // Collapsed separators, split gaps or a repaired write may have changed signed turns
+        resign(&chat_context.path, &last_content, chat_context.altered_turn).await?;
+    }
+    Ok(())
+}
+
+/// The phases of one sent turn as OpenTelemetry spans: a root span for the turn with a
+/// child per phase.
+struct TurnTrace {
+    trace_id: String,
+    root_id: String,
+    start: SystemTime,
+    spans: Vec<serde_json::Value>,
+}
+
+impl TurnTrace {
+    fn new(start: SystemTime) -> Self {
+        Self {
+            trace_id: random_hex(16),
+            root_id: random_hex(8),
+            start,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Adds a finished phase under the turn's root span.
+    fn phase(
+        &mut self,
+        name: &str,
+        start: SystemTime,
+        end: SystemTime,
+        attributes: &[(&str, serde_json::Value)],
+        error: Option<&str>,
+    ) {
+        let span = otlp_span(&self.trace_id, &random_hex(8), Some(&self.root_id), name, start, end, attributes, error);
+        self.spans.push(span);
+    }
+
+    /// Closes the root span and sends the trace in the background; a collector that can't
+    /// be reached is only logged.
+    fn export(
+        mut self,
+        client: &reqwest::Client,
+        config: &Config,
+        attributes: &[(&str, serde_json::Value)],
+        error: Option<&str>,
+    ) {
+        let Some(url) = config.otlp_url.clone() else {
+            return;
+        };
+        let root = otlp_span(&self.trace_id, &self.root_id, None, "turn", self.start, SystemTime::now(), attributes, error);
+        self.spans.insert(0, root);
+        let body = serde_json::json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [otlp_attribute("service.name", &config.service_name.clone().into())] },
+                "scopeSpans": [{ "scope": { "name": DEFAULT_SERVICE_NAME }, "spans": self.spans }],
+            }],
+        });
+        let request = config
+            .otlp_headers
+            .iter()
+            .fold(client.post(&url).json(&body), |request, (name, value)| request.header(name, value));
+        tokio::spawn(async move {
+            let result = request.send().await.and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                debug_log(&format!("error: could not export trace: {}", e));
+            }
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn otlp_span(
+    trace_id: &str,
+    span_id: &str,
+    parent_id: Option<&str>,
+    name: &str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: &[(&str, serde_json::Value)],
+    error: Option<&str>,
+) -> serde_json::Value {
+    let nanos = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+    let status = match error {
+        Some(message) => serde_json::json!({ "code": 2, "message": message }),
+        None => serde_json::json!({ "code": 1 }),
+    };
+    serde_json::json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "parentSpanId": parent_id.unwrap_or_default(),
+        "name": name,
+        "kind": 1,
+        "startTimeUnixNano": nanos(start),
+        "endTimeUnixNano": nanos(end),
+        "attributes": attributes.iter().map(|(key, value)| otlp_attribute(key, value)).collect::<Vec<_>>(),
+        "status": status,
+    })
+}
+
+/// An OTLP `KeyValue`; integers are sent as strings, as the JSON encoding requires.
+fn otlp_attribute(key: &str, value: &serde_json::Value) -> serde_json::Value {
+    let value = match value {
+        serde_json::Value::Bool(flag) => serde_json::json!({ "boolValue": flag }),
+        serde_json::Value::Number(number) if number.is_u64() || number.is_i64() => {
+            serde_json::json!({ "intValue": number.to_string() })
+        }
+        serde_json::Value::Number(number) => serde_json::json!({ "doubleValue": number }),
+        other => serde_json::json!({ "stringValue": other.as_str().map_or_else(|| other.to_string(), str::to_string) }),
+    };
+    serde_json::json!({ "key": key, "value": value })
+}
+
+/// `bytes` random bytes as lowercase hex, for trace and span IDs.
+fn random_hex(bytes: usize) -> String {
+    (0..bytes).map(|_| format!("{:02x}", rand::random::<u8>())).collect()
+}
+
+/// POSTs a finished turn to the webhook, retrying a few times. Runs in the background, so
+/// failures are only logged and never hold up the chat.
+async fn deliver_webhook(client: reqwest::Client, url: String, payload: WebhookPayload) {
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let result = client
+            .post(&url)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => return,
+            Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                debug_log(&format!("skip: webhook attempt {} failed: {}", attempt, e));
+                tokio::time::sleep(WEBHOOK_RETRY_DELAY * attempt).await;
+            }
+            Err(e) => debug_log(&format!("error: webhook delivery failed: {}", e)),
+        }
+    }
+}
+
+/// The files the environment is loaded from, lowest precedence first.
+fn env_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from(".env"), PathBuf::from(".env.local")];
+    if let Some(file) = env_string("CHAT_ENV") {
+        files.push(PathBuf::from(file));
+    }
+    files
+}
+
+/// Loads `.env`, `.env.local` and the file named by `CHAT_ENV`, later ones winning.
+/// `dotenv` never overrides a variable that is already set, so they load in reverse.
+fn load_env_files() {
+    for file in env_files().iter().rev() {
+        match dotenv::from_filename(file) {
+            Ok(_) => debug_log(&format!("load: environment from {}", file.display())),
+            Err(dotenv::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => debug_log(&format!("error: could not load {}: {}", file.display(), e)),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse()?;
+    load_env_files();
+
+    if args.once {
+        LOG_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    configure_colors();
+
+    let config = Arc::new(Config::from_env()?);
+    init_separators()?;
+
+    if let Some(path) = &args.migrate_separator {
+        return migrate_separator(path, config.newest_first).await;
+    }
+
+    let provider = Provider {
+        model: config.model.clone(),
+        supports_developer_role: config.developer_role,
+        reasoning_effort: config.reasoning_effort,
+        reasoning_field: config.reasoning_field.clone(),
+        shell_tool: args.allow_tools && !config.tool_allowlist.is_empty(),
+        search_tool: config.search_tool.clone(),
+        user: config.user.clone(),
+        extra_body: config.extra_body.clone(),
+        redactions: config.redactions.clone(),
+        request_transform: config.request_transform.clone(),
+        response_transform: config.response_transform.clone(),
+    };
+    if args.allow_tools && config.tool_allowlist.is_empty() {
+        debug_log("skip: --allow-tools given but CHAT_TOOL_ALLOWLIST is empty, tools stay off");
+    } else if !args.allow_tools && !config.tool_allowlist.is_empty() {
+        debug_log("skip: CHAT_TOOL_ALLOWLIST set but tools need --allow-tools to run");
+    }
+    let api_client = Arc::new(ApiClient::new(
+        config.api_keys.clone(),
+        config.api_url.clone(),
+        provider,
+        config.empty_retries,
+        config.rate_limit_floor,
+        config
+            .error_budget
+            .map(|limit| ErrorBudget::new(limit, config.error_window)),
+    ));
+
+    if args.check {
+        return run_check(&api_client).await;
+    }
+
+    if args.once {
+        let mut chat_context = ChatContext::new(
+            String::new(),
+            config.chat_file.clone(),
+            config.instructions.clone(),
+            config.plain_text,
+            config.memory_file.clone(),
+            config.stream,
+        );
+        return run_once(&api_client, &config, &mut chat_context).await;
+    }
+
+    if is_ignored(&config.chat_file).await {
+        debug_log(&format!(
+            "skip: {} matches {}; edits will not be sent",
+            config.chat_file.display(),
+            IGNORE_FILE
+        ));
+    }
+    // `--from-url` always downloads; a URL in `CHAT_FILE` only seeds a chat file that isn't
+    // there yet, so restarting doesn't throw away the conversation held in it
+    let source_url = match (&args.from_url, &config.source_url) {
+        (Some(url), _) => Some(url),
+        (None, Some(url)) if !fs::try_exists(&config.chat_file).await? => Some(url),
+        (None, Some(url)) => {
+            debug_log(&format!("skip: {} exists, not downloading {} again", config.chat_file.display(), url));
+            None
+        }
+        (None, None) => None,
+    };
+    if let Some(url) = source_url {
+        let content = fetch_conversation(&api_client.client, url).await?;
+        if let Ok(existing) = read_chat_file(&config.chat_file, config.newest_first).await {
+            let backup = backup_chat_file(&config.chat_file, &existing, config.newest_first).await?;
+            debug_log(&format!("write: backed up {} to {}", config.chat_file.display(), backup.display()));
+        }
+        write_chat_file(&config.chat_file, &content, config.newest_first).await?;
+        debug_log(&format!("load: conversation from {} into {}", url, config.chat_file.display()));
+    }
+    if !fs::try_exists(&config.chat_file).await? {
+        if args.no_create {
+            anyhow::bail!("{} does not exist", config.chat_file.display());
+        }
+        create_chat_file(&config.chat_file, config.template.as_deref(), config.newest_first).await?;
+    }
+    // The pending marker's offset is into the merged conversation, not the input file
+    if !config.reply_file {
+        recover_interrupted_reply(&config.chat_file, config.newest_first).await?;
+    }
+    if config.save_draft {
+        recover_draft(&config.chat_file, config.reply_file, config.newest_first).await?;
+    }
+    set_status(&config, &config.output_path(&config.chat_file), "idle", None).await;
+    let initial_content = fs::read_to_string(&config.chat_file).await.unwrap_or_default();
+    let initial_ordered = from_file_order(initial_content.clone(), config.newest_first);
+    let mut altered = None;
+    if config.sign {
+        let conversation = read_conversation(&config.chat_file, config.reply_file, config.newest_first).await?;
+        check_signature(&config.chat_file, &conversation, &mut altered).await?;
+    }
+    let chat_context = Arc::new(Mutex::new(ChatContext::new(
+        initial_content.clone(),
+        config.chat_file.clone(),
+        config.instructions.clone(),
+        config.plain_text,
+        config.memory_file.clone(),
+        config.stream,
+    )));
+    chat_context.lock().await.altered_turn = altered;
+    let mut reader = ChatFileReader::new(initial_content.clone());
+    let last_content = Arc::new(Mutex::new(initial_ordered));
+
+    let (tx, mut rx) = mpsc::channel(10);
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = running.clone();
+
+    if let Some(port) = args.metrics_port {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .with_context(|| format!("Failed to serve metrics on port {}", port))?;
+        debug_log(&format!("init: metrics on http://127.0.0.1:{}/metrics", port));
+        tokio::spawn(serve_metrics(listener));
+    }
+    if config.reload_key {
+        tokio::spawn(reload_keys(api_client.clone()));
+    }
+
+    let mut watcher = RecommendedWatcher::new(
+        move |res: Result<Event, notify::Error>| {
+            if let Ok(event) = res {
+                if event.kind.is_modify() || event.kind.is_create() {
+                    // Stamped here: events can sit in the channel through a long API call
+                    let _ = tx.blocking_send((event.paths, Instant::now()));
+                }
+            }
+        },
+        notify::Config::default(),
+    )?;
+
+    // Watch the directory rather than the file: atomic saves (ours and most editors') replace
+    // the file with a new one, which would silently end a watch on the old inode
+    let mut watched = config.chat_file.clone();
+    watcher.watch(chat_dir(&watched), RecursiveMode::NonRecursive)?;
+    // Whether the status line shows an error from the last event
+    let mut errored = false;
+
+    debug_log("init: chat monitor started");
+    println!("Monitoring {} for new messages...", watched.display());
+    println!("Type your message and press Enter twice to send.");
+
+    let mut last_event_time = Instant::now();
+    let mut recent_changes: HashMap<PathBuf, Instant> = HashMap::new();
+    // A removed directory sends no more events, so look for it on a timer
+    let mut dir_check = tokio::time::interval(MISSING_DIR_INTERVAL);
+    dir_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut missing_checks = 0;
+    while running.load(Ordering::SeqCst) {
+        tokio::select! {
+            Some((paths, changed_at)) = rx.recv() => {
+                recent_changes.retain(|_, changed| changed_at.saturating_duration_since(*changed) < config.bulk_window);
+                let counted = paths.iter().filter(|path| !is_incidental_file(path, &watched));
+                recent_changes.extend(counted.map(|path| (path.clone(), changed_at)));
+
+                let chat_name = watched.file_name();
+                if !paths.iter().any(|path| path.file_name() == chat_name) {
+                    continue;
+                }
+                if last_event_time.elapsed() < Duration::from_millis(50) {
+                    continue;
+                }
+                last_event_time = Instant::now();
+
+                // A person edits one file; a checkout or pull rewrites many at once. Wait out
+                // the window to see which this is before sending anything
+                if config.bulk_files > 0 {
+                    let deadline = tokio::time::Instant::now() + config.bulk_window;
+                    while let Ok(Some((more, changed_at))) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                        let more = more.into_iter().filter(|path| !is_incidental_file(path, &watched));
+                        recent_changes.extend(more.map(|path| (path, changed_at)));
+                    }
+                    if recent_changes.len() >= config.bulk_files {
+                        debug_log(&format!(
+                            "skip: {} files changed at once, likely a VCS operation",
+                            recent_changes.len()
+                        ));
+                        let content = fs::read_to_string(&watched).await.unwrap_or_default();
+                        reader = ChatFileReader::new(content.clone());
+                        *last_content.lock().await = from_file_order(content, config.newest_first);
+                        continue;
+                    }
+                }
+
+                debug_log("detect: file change");
+                let content = match reader.read(&watched).await {
+                    Ok(content) => from_file_order(content, config.newest_first),
+                    // Left to the directory check below
+                    Err(e) if chat_dir_gone(&watched).await => {
+                        debug_log(&format!("error: {}", e));
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                match process_new_messages(
+                    content,
+                    last_content.clone(),
+                    api_client.clone(),
+                    chat_context.clone(),
+                    config.clone(),
+                ).await {
+                    Ok(()) if errored => {
+                        // The error was dealt with; the next event went through
+                        set_status(&config, &config.output_path(&watched), "idle", None).await;
+                        errored = false;
+                    }
+                    Ok(()) => {}
+                    Err(e) => {
+                        debug_log(&format!("error: {:#}", e));
+                        METRICS.errors.fetch_add(1, Ordering::Relaxed);
+                        let status = format!("error: {}", one_line(&format!("{:#}", e), PREVIEW_CHARS));
+                        set_status(&config, &config.output_path(&watched), &status, None).await;
+                        errored = true;
+                        // Returned rather than exiting here, so the watcher and everything
+                        // else in scope is dropped on the way out
+                        if args.exit_on_error {
+                            return Err(e);
+                        }
+                    }
+                }
+
+                // Follow a `/switch` to another chat file
+                let active = chat_context.lock().await.path.clone();
+                if active != watched {
+                    if chat_dir(&active) != chat_dir(&watched) {
+                        watcher.unwatch(chat_dir(&watched))?;
+                        watcher.watch(chat_dir(&active), RecursiveMode::NonRecursive)?;
+                    }
+                    let content = fs::read_to_string(&active).await?;
+                    reader = ChatFileReader::new(content.clone());
+                    *last_content.lock().await = from_file_order(content, config.newest_first);
+                    debug_log(&format!("monitoring: {}", active.display()));
+                    watched = active;
+                }
+            }
+            _ = dir_check.tick() => {
+                if !chat_dir_gone(&watched).await {
+                    missing_checks = 0;
+                    continue;
+                }
+                missing_checks += 1;
+                if missing_checks < MISSING_DIR_CHECKS {
+                    continue;
+                }
+                missing_checks = 0;
+                if !config.wait_for_dir {
+                    anyhow::bail!(
+                        "{} and its directory were removed; set CHAT_ON_MISSING_DIR=wait to wait for them instead",
+                        watched.display()
+                    );
+                }
+
+                debug_log(&format!("error: {} was removed, waiting for it to come back", chat_dir(&watched).display()));
+                if !wait_for_chat_file(&watched).await {
+                    debug_log("Shutting down...");
+                    break;
+                }
+                // The old watch ended with the directory; this one is on the new directory
+                let _ = watcher.unwatch(chat_dir(&watched));
+                watcher.watch(chat_dir(&watched), RecursiveMode::NonRecursive)?;
+                let content = fs::read_to_string(&watched).await.unwrap_or_default();
+                reader = ChatFileReader::new(content.clone());
+                *last_content.lock().await = from_file_order(content, config.newest_first);
+                debug_log(&format!("monitoring: {} again", watched.display()));
+            }
+            _ = tokio::signal::ctrl_c() => {
+                debug_log("Shutting down...");
+                running_clone.store(false, Ordering::SeqCst);
+                break;
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_client(api_url: String) -> ApiClient {
+        let provider = Provider {
+            model: "test-model".to_string(),
+            supports_developer_role: false,
+            reasoning_effort: None,
+            reasoning_field: DEFAULT_REASONING_FIELD.to_string(),
+            shell_tool: false,
+            search_tool: None,
+            user: None,
+            extra_body: serde_json::Map::new(),
+            redactions: Vec::new(),
+            request_transform: None,
+            response_transform: None,
+        };
+        ApiClient::new(vec![SecretString::from("test-key")], api_url, provider, 0, 0, None)
+    }
+
+    /// The configuration from the environment, with the one variable it requires set.
+    fn test_config() -> Config {
+        std::env::set_var("DEEPSEEK_API_KEY", "test-key");
+        Config::from_env().unwrap()
+    }
+
+    /// Answers each connection with the next of `responses`, a status and JSON body; `None`
+    /// reads the request and hangs up without answering. Returns the endpoint URL.
+    async fn mock_api(responses: Vec<Option<(u16, String)>>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                read_request(&mut socket).await;
+                if let Some((status, body)) = response {
+                    let reply = format!(
+                        "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(reply.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    async fn read_request(socket: &mut tokio::net::TcpStream) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:")?.trim().parse().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return;
+                }
+            }
+            if read == 0 {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_is_retried_transparently() {
+        let reply = r#"{"choices": [{"message": {"role": "assistant", "content": "hello"}}]}"#;
+        let url = mock_api(vec![None, Some((200, reply.to_string()))]).await;
+        let completion = test_client(url).call_api(vec![Message::new(Role::User, "hi")], false).await.unwrap();
+        assert_eq!(completion.content, "hello");
+    }
+
+    #[test]
+    fn allow_list_entries_with_arguments_fix_them() {
+        assert!(is_allowed("ls", "ls", &["-la"]));
+        assert!(is_allowed("git status", "git", &["status", "--short"]));
+        assert!(!is_allowed("git status", "git", &["-c", "alias.x=!sh", "x"]));
+        assert!(!is_allowed("git status", "git", &[]));
+        assert!(!is_allowed("ls", "lsof", &[]));
+    }
+
+    #[test]
+    fn plain_names_stay_in_their_directory() {
+        assert!(is_plain_name("notes"));
+        assert!(is_plain_name("notes.md"));
+        for name in ["../notes", "/etc/passwd", "sub/notes", "..", ".", ""] {
+            assert!(!is_plain_name(name), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn reader_sees_an_edit_saved_together_with_an_append() {
+        let path = std::env::temp_dir().join(format!("chat-md-reader-{}.md", std::process::id()));
+        let mut reader = ChatFileReader::new("first draft\n".repeat(20));
+        let edited = format!("{}and more\n", "first DRAFT\n".repeat(20));
+        std::fs::write(&path, &edited).unwrap();
+        assert_eq!(reader.read(&path).await.unwrap(), edited);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reply_boundary_survives_a_restart() {
+        // Nothing is carried over from the run that wrote the reply, only the file
+        let written = append_turn("Hi\n", &with_reply_end("Hello!", ReplyEnding::BlankLine), ReplyEnding::BlankLine);
+        assert_eq!(split_after_reply(&written), None);
+        let typed = format!("{}And then?\n", written);
+        assert_eq!(split_after_reply(&typed).unwrap(), "Hi\n***\nHello!\n***\nAnd then?\n");
+    }
+
+    #[test]
+    fn reply_boundary_survives_an_edit_to_the_reply() {
+        let written = append_turn("Hi\n", &with_reply_end("Hello!", ReplyEnding::Nothing), ReplyEnding::Nothing);
+        let edited = written.replace("Hello!", "Hello, edited.") + "Next\n";
+        assert_eq!(split_after_reply(&edited).unwrap(), "Hi\n***\nHello, edited.\n***\nNext\n");
+    }
+
+    #[tokio::test]
+    async fn includes_stay_inside_the_chat_directory() {
+        let root = std::env::temp_dir().join(format!("chat-md-include-{}", std::process::id()));
+        let dir = root.join("chat");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "ok").unwrap();
+        std::fs::write(root.join(".env"), "SECRET=1").unwrap();
+
+        assert!(path_within(&dir, "notes.txt").await.is_ok());
+        assert!(path_within(&dir, "./notes.txt").await.is_ok());
+        assert!(path_within(&dir, "../.env").await.is_err());
+        assert!(path_within(&dir, &root.join(".env").display().to_string()).await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snippets_stay_inside_the_snippet_directory() {
+        let dir = std::env::temp_dir();
+        let error = expand_snippets("/snippet ../../secrets", &dir).await.unwrap_err();
+        assert!(error.to_string().contains("path"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn transform_takes_input_larger_than_the_pipe_buffer() {
+        // `cat` prints as it reads, so this stalls unless stdin is written while stdout is read
+        let input = serde_json::json!({ "messages": "x".repeat(512 * 1024) });
+        assert_eq!(run_transform("cat", input.clone()).await.unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn reader_reads_only_the_tail_when_the_file_grew() {
+        let path = std::env::temp_dir().join(format!("chat-md-tail-{}.md", std::process::id()));
+        // Beyond the checked window, so the stale start shows what was not read again
+        let mut reader = ChatFileReader::new(format!("{}hello\n", "x".repeat(2 * APPEND_CHECK_BYTES)));
+        let stale = format!("{}{}", "y".repeat(APPEND_CHECK_BYTES), "x".repeat(APPEND_CHECK_BYTES));
+        std::fs::write(&path, format!("{}hello\nworld\n", stale)).unwrap();
+        let read = reader.read(&path).await.unwrap();
+        assert!(read.starts_with('x') && read.ends_with("hello\nworld\n"));
+        std::fs::write(&path, "hi\n").unwrap();
+        assert_eq!(reader.read(&path).await.unwrap(), "hi\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn message_content_can_be_a_string() {
+        let message: Message = serde_json::from_str(r#"{"role": "assistant", "content": "hello"}"#).unwrap();
+        assert_eq!(message.content, "hello");
+        let message: Message = serde_json::from_str(r#"{"role": "assistant", "content": null}"#).unwrap();
+        assert_eq!(message.content, "");
+    }
+
+    #[test]
+    fn message_content_blocks_are_joined() {
+        let json = r#"{"role": "assistant", "content": [
+            {"type": "text", "text": "Hello, "},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+            {"type": "text", "text": "world"}
+        ]}"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+        assert_eq!(message.content, "Hello, world");
+    }
+
+    #[test]
+    fn messy_replies_parse_back_as_one_turn() {
+        let content = "Hi\n";
+        for reply in ["\n\n  Hello  \n\n\n", "One\n\n\n\nTwo\n\n\n", "Above\n***\nBelow", "```\na\n\n\n\nb\n```\n\n"] {
+            let written = append_turn(content, reply, ReplyEnding::Separator);
+            let turns = split_turns(&written);
+            assert_eq!(turns.len(), 3, "{:?}", written);
+            assert_eq!(turns[1].role, Role::Assistant);
+        }
+    }
+
+    #[test]
+    fn blank_runs_inside_code_blocks_are_kept() {
+        assert_eq!(canonical_reply("```\na\n\n\nb\n```"), "```\na\n\n\nb\n```");
+        assert_eq!(canonical_reply("a\n\n\n\nb"), "a\n\nb");
+    }
+
+    #[test]
+    fn send_marker_mid_paragraph_keeps_the_rest_unsent() {
+        let content = "Hi\n***\nHello!\n***\nWhat does this do? <!-- send --> and this half\nis still a draft\n";
+        let (sent, draft) = split_at_send_marker(content).unwrap();
+        assert_eq!(sent, "Hi\n***\nHello!\n***\nWhat does this do?\n\n");
+        assert_eq!(draft, "and this half\nis still a draft\n");
+        let context = ChatContext::new(String::new(), PathBuf::from("chat.md"), None, false, PathBuf::new(), false);
+        assert_eq!(context.extract_new_message(&sent, sent.trim_end().len(), &test_config()), "What does this do?");
+    }
+
+    #[test]
+    fn send_marker_in_code_or_earlier_turns_is_ignored() {
+        assert!(split_at_send_marker("Hi <!-- send --> there\n***\nReply\n***\nNext\n").is_none());
+        assert!(split_at_send_marker("```\n<!-- send -->\n```\n").is_none());
+    }
+
+    #[test]
+    fn newest_first_files_round_trip() {
+        let files = [
+            "Next question\n\n\n***\nAnswer\n***\nFirst question\n",
+            "***\nAnswer\n***\nFirst question\n",
+            "---\nmodel: x\n---\nStill typing\n***\nAnswer\n***\nFirst question\n",
+        ];
+        for file in files {
+            let ordered = from_file_order(file.to_string(), true);
+            assert!(ordered.find("First question") < ordered.find("Answer"), "{:?}", ordered);
+            assert_eq!(to_file_order(&ordered, true), file);
+        }
+        let ordered = from_file_order(files[0].to_string(), true);
+        assert_eq!(ordered, "First question\n***\nAnswer\n***\nNext question\n\n");
+        assert!(ends_with_blank_line(&ordered));
+    }
+
+    #[test]
+    fn oldest_first_files_are_left_alone() {
+        let file = "Next question\n***\nAnswer\n***\nFirst question\n";
+        assert_eq!(from_file_order(file.to_string(), false), file);
+        assert_eq!(to_file_order(file, false), file);
+    }
+
+    #[test]
+    fn append_read_halfway_through_a_character_waits_for_the_rest() {
+        let mut reader = ChatFileReader::new("Hi ".to_string());
+        let tail = "café 🦀\n".as_bytes();
+        let crab = tail.len() - "🦀\n".len();
+        assert_eq!(reader.append(tail[..crab].to_vec()).unwrap(), "Hi café ");
+        assert_eq!(reader.append(tail[crab..crab + 2].to_vec()).unwrap(), "Hi café ");
+        assert_eq!(reader.len(), "Hi café ".len() + 2);
+        assert_eq!(reader.append(tail[crab + 2..].to_vec()).unwrap(), "Hi café 🦀\n");
+    }
+
+    #[tokio::test]
+    async fn tail_read_halfway_through_a_character_continues_after_it() {
+        let path = std::env::temp_dir().join(format!("chat-md-utf8-{}.md", std::process::id()));
+        let mut reader = ChatFileReader::new("Hi ".to_string());
+        let whole = "Hi café 🦀\n".as_bytes();
+        let crab = whole.len() - "🦀\n".len();
+        std::fs::write(&path, &whole[..crab + 2]).unwrap();
+        assert_eq!(reader.read(&path).await.unwrap(), "Hi café ");
+        std::fs::write(&path, whole).unwrap();
+        assert_eq!(reader.read(&path).await.unwrap(), "Hi café 🦀\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn whole_file_read_halfway_through_a_character_drops_only_that_character() {
+        let mut reader = ChatFileReader::new("Hello, world".to_string());
+        let edited = "Hallo, café".as_bytes();
+        assert_eq!(reader.replace(edited[..edited.len() - 1].to_vec()).unwrap(), "Hallo, caf");
+        assert_eq!(reader.replace(edited.to_vec()).unwrap(), "Hallo, café");
+        assert!(reader.replace(b"Hallo, \xff caf".to_vec()).is_err());
+    }
+
+    #[test]
+    fn doubled_separators_collapse_without_shifting_roles() {
+        let content = "Hi\n***\nHello!\n***\n\n***\nHow are you?\n\n";
+        let (collapsed, dropped) = collapse_separators(content).unwrap();
+        assert_eq!(dropped, 1);
+        assert_eq!(collapsed, "Hi\n***\nHello!\n***\nHow are you?\n\n");
+        let roles: Vec<Role> = split_turns(&collapsed).iter().map(|turn| turn.role).collect();
+        assert_eq!(roles, [Role::User, Role::Assistant, Role::User]);
+    }
+
+    #[test]
+    fn blank_open_turn_and_separators_in_code_are_not_collapsed() {
+        assert!(collapse_separators("Hi\n***\nHello!\n***\n").is_none());
+        assert!(collapse_separators("Hi\n```\n***\n\n***\n```\n***\nHello!\n***\n").is_none());
+        let (collapsed, dropped) = collapse_separators("Hi\n***\n***\n\n***\nHello!\n***\n").unwrap();
+        assert_eq!((collapsed.as_str(), dropped), ("Hi\n***\nHello!\n***\n", 2));
+    }
+
+    #[test]
+    fn open_turn_found_from_the_tail_matches_a_full_parse() {
+        let edits = [
+            "Hi\n***\nHello!\n***\nWhat ",
+            "Hi\n***\nHello!\n***\nWhat about\n```\n***\n",
+            "Hi\n***\nHello!\n***\nWhat about\n```\n***\n```\n***\nReply\n***\n",
+            "Hi\n***\nHello!\n***\nWhat about\n```\n***\n```\n***\nReply\n***\nThanks",
+            "Hi\n***\nHello!\n***\n",
+            "> SYSTEM: Be brief.\n***\nHi\n***\nHello!\n***\n",
+            "> SYSTEM: Be brief.\n***\nHi\n***\nHello!\n***\nMore\n***\nFine.\n***\n",
+        ];
+        let mut known = None;
+        for content in edits {
+            let last = split_turns(content).pop().unwrap();
+            let (start, role) = open_turn(content, known.as_ref());
+            assert_eq!((start, role), (last.start, last.role), "{:?}", content);
+            known = Some((content[..start].to_string(), role));
+        }
+    }
+
+    #[tokio::test]
+    async fn emptied_turn_removes_its_draft() {
+        let path = std::env::temp_dir().join(format!("chat-md-draft-{}.md", std::process::id()));
+        let mut context = ChatContext::new(String::new(), path.clone(), None, false, PathBuf::new(), false);
+        context.update_draft("Hi\n***\nHello!\n***\nA long prompt", false).await;
+        assert_eq!(std::fs::read_to_string(draft_path(&path)).unwrap(), "A long prompt");
+        context.update_draft("Hi\n***\nHello!\n***\n", false).await;
+        assert!(!draft_path(&path).exists());
+    }
+
+    /// Per-keystroke cost of a 5MB single-line paste; `cargo test --release huge_line -- --nocapture`
+    /// prints the timings.
+    #[tokio::test]
+    async fn keystrokes_in_a_huge_line_stay_fast() {
+        let config = Arc::new(test_config());
+        let api_client = Arc::new(test_client("http://127.0.0.1:9/v1".to_string()));
+        let path = std::env::temp_dir().join(format!("chat-md-bench-{}.md", std::process::id()));
+        let chat_context = Arc::new(Mutex::new(ChatContext::new(
+            String::new(),
+            path,
+            None,
+            false,
+            PathBuf::from("memory.md"),
+            false,
+        )));
+        let record = r#"{"id":12345,"name":"item","tags":["a","b","c"],"nested":{"ok":true}},"#;
+        let mut content = format!("Hi\n***\nHello!\n***\n[{}", record.repeat(5_000_000 / record.len()));
+        let last_content = Arc::new(Mutex::new(content.clone()));
+
+        let mut slowest = Duration::ZERO;
+        let started = Instant::now();
+        for c in "{\"id\":1}".chars() {
+            content.push(c);
+            let event = Instant::now();
+            let (context, client) = (chat_context.clone(), api_client.clone());
+            process_new_messages(content.clone(), last_content.clone(), client, context, config.clone()).await.unwrap();
+            slowest = slowest.max(event.elapsed());
+        }
+        println!("5MB line: {:?} per keystroke, slowest {:?}", started.elapsed() / 8, slowest);
+        assert_eq!(*last_content.lock().await, content);
+        assert!(slowest < Duration::from_millis(50), "slowest keystroke took {:?}", slowest);
+    }
+
+    #[tokio::test]
+    async fn network_errors_show_their_cause_once() {
+        let cause = reqwest::get("http://127.0.0.1:9/").await.unwrap_err();
+        let shown = format!("{:#}", anyhow::Error::from(ChatError::Network(cause)));
+        assert!(shown.starts_with("Network error: "), "{}", shown);
+        assert_eq!(shown.matches("127.0.0.1:9").count(), 1, "{}", shown);
+    }
+
+    #[test]
+    fn word_diff_marks_removed_and_added_words() {
+        let diff = |old, new| render_word_diff(&similar::TextDiff::from_words(old, new));
+        assert_eq!(diff("The quick brown fox", "The slow brown fox"), "The ~~quick~~ **slow** brown fox");
+        assert_eq!(diff("a b c", "a c"), "a ~~b~~ c");
+        assert_eq!(diff("one two", "one two three four"), "one two **three four**");
+        assert_eq!(diff("Same\ntext", "Same\ntext"), "Same\ntext");
+    }
+
+    #[test]
+    fn otlp_headers_are_decoded() {
+        let headers = parse_otlp_headers("OTEL_EXPORTER_OTLP_HEADERS", "Api-Key=abc%3D%3D, x-team = a%20b,").unwrap();
+        let expected = [("api-key", "abc=="), ("x-team", "a b")];
+        assert_eq!(headers, expected.map(|(name, value)| (name.to_string(), value.to_string())));
+        assert_eq!(percent_decode("100% sure%2"), "100% sure%2");
+        assert!(parse_otlp_headers("OTEL_EXPORTER_OTLP_HEADERS", "no-value").is_err());
+    }
+
+    #[test]
+    fn trace_ids_are_random_hex() {
+        let (first, second) = (random_hex(16), random_hex(16));
+        assert_eq!(first.len(), 32);
+        assert!(first.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn own_and_editor_files_are_not_a_bulk_change() {
+        let chat = Path::new("/notes/chat.md");
+        let incidental = [
+            ".chat.md.tmp", ".chat-backups", "chat.md.sig", "chat.usage.json", "chat.md.draft", "chat.md.draft.old",
+            "chat.reply.md", ".chat.md.pending", ".chat.md.id",
+            ".chat.md.swp", "chat.md~", "4913", ".#chat.md", "#chat.md#",
+        ];
+        for name in incidental {
+            assert!(is_incidental_file(&Path::new("/notes").join(name), chat), "{}", name);
+        }
+        for name in ["chat.md", "README.md", "src.rs", "chatter.md", "chat.rs", "chat.txt"] {
+            assert!(!is_incidental_file(&Path::new("/notes").join(name), chat), "{}", name);
+        }
+    }
+
+    #[test]
+    fn commands_answered_in_the_reply_file_are_not_history() {
+        let input = "/timing\n***\nHello\n***\nWhat now?\n\n";
+        let replies = append_reply(&append_reply("", &note_block("timing: on")), "Hi!");
+        let merged = merge_replies(input, &replies);
+        let context = ChatContext::new(String::new(), PathBuf::from("chat.md"), None, false, PathBuf::new(), false);
+        let history = context.history(&merged, merged.trim_end().len(), &test_config());
+        let sent: Vec<(Role, &str)> = history.iter().map(|message| (message.role, message.content.as_str())).collect();
+        assert_eq!(sent, [(Role::User, "Hello"), (Role::Assistant, "Hi!")]);
+    }
+
+    #[tokio::test]
+    async fn rewrites_by_the_monitor_are_signed_again() {
+        let path = std::env::temp_dir().join(format!("chat-md-sign-{}.md", std::process::id()));
+        let signed = "Hi <!-- ask: what is 2+2? -->\n***\nHello!\n***\n";
+        sign_turns(&path, signed).await.unwrap();
+        let answered = "Hi 4\n***\nHello!\n***\nNext\n***\nReply\n***\n";
+        assert_eq!(altered_turn(&path, answered).await.unwrap(), Some(0));
+        resign(&path, answered, None).await.unwrap();
+        assert_eq!(altered_turn(&path, answered).await.unwrap(), None);
+
+        // An outside edit stays reported after the monitor's own rewrite
+        let edited = "Hi, edited\n***\nHello!\n***\nNext\n***\nReply\n***\n";
+        resign(&path, edited, Some(0)).await.unwrap();
+        assert_eq!(altered_turn(&path, edited).await.unwrap(), Some(0));
+        std::fs::remove_file(signature_path(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn inline_asks_are_only_answered_in_user_turns() {
+        let reply = r#"{"choices":[{"message":{"role":"assistant","content":"4"}}]}"#;
+        let url = mock_api(vec![Some((200, reply.to_string()))]).await;
+        let content = "Sum <!-- ask: 2+2? -->\n***\nWrite `<!-- ask: 3+3? -->` to ask\n***\n\
+                       ```\n<!-- ask: 5+5? -->\n```\n";
+        let (answered, asked) = answer_inline_asks(content, &test_client(url)).await.unwrap().unwrap();
+        assert_eq!(asked, vec![("2+2?".to_string(), "4".to_string())]);
+        assert!(answered.starts_with("Sum 4\n***\nWrite `<!-- ask: 3+3? -->`"));
+        assert!(answered.contains("<!-- ask: 5+5? -->"));
+    }
+
+    #[test]
+    fn only_the_turns_own_wrapper_is_taken_off() {
+        let mut config = test_config();
+        config.assistant_wrapper = Some(Wrapper::Blockquote);
+        let context = ChatContext::new(String::new(), PathBuf::from("chat.md"), None, false, PathBuf::new(), false);
+        let content = "> Is this quote right?\n***\n> It is.\n***\n";
+        let history = context.parse_messages(content, &config);
+        let sent: Vec<(Role, &str)> = history.iter().map(|message| (message.role, message.content.as_str())).collect();
+        assert_eq!(sent, [(Role::User, "> Is this quote right?"), (Role::Assistant, "It is.")]);
+    }
+
+    #[test]
+    fn only_separators_markdown_reads_otherwise_are_flagged() {
+        for documented in ["***", "___", "* * *", "<!-- turn -->", "%%%"] {
+            assert_eq!(markdown_conflict(documented), None, "{}", documented);
+        }
+        assert_eq!(markdown_conflict("==="), Some("a heading"));
+        assert_eq!(markdown_conflict("# next"), Some("a heading"));
+        assert_eq!(markdown_conflict("- next"), Some("a list"));
+        assert_eq!(markdown_conflict("> next"), Some("a blockquote"));
+        assert_eq!(markdown_conflict("~~~"), Some("a code fence"));
+    }
+
+    #[tokio::test]
+    async fn search_is_only_dropped_when_the_request_is_refused() {
+        let reply = r#"{"choices": [{"message": {"role": "assistant", "content": "hello"}}]}"#;
+        let url = mock_api(vec![Some((400, "{}".to_string())), Some((200, reply.to_string()))]).await;
+        let completion = test_client(url).call_api(vec![Message::new(Role::User, "hi")], true).await.unwrap();
+        assert_eq!(completion.content, "hello");
+
+        let url = mock_api(vec![Some((404, "{}".to_string()))]).await;
+        let result = test_client(url).call_api(vec![Message::new(Role::User, "hi")], true).await;
+        assert!(matches!(result, Err(ChatError::Status(reqwest::StatusCode::NOT_FOUND))));
+    }
+
+    #[tokio::test]
+    async fn numbered_includes_stay_within_the_budget() {
+        let path = std::env::temp_dir().join(format!("chat-md-include-{}.txt", std::process::id()));
+        let text: Vec<String> = (0..400).map(|i| format!("line {}", i)).collect();
+        std::fs::write(&path, text.join("\n")).unwrap();
+        // Fits as plain text, but not with `NNN | ` on every line
+        let budget = estimate_tokens(&text.join("\n")) + 10;
+        let plain = include_file(&path, "notes.txt", budget, false).await.unwrap();
+        assert!(!plain.contains("omitted"));
+        let numbered = include_file(&path, "notes.txt", budget, true).await.unwrap();
+        assert!(numbered.contains("omitted"));
+        let kept: usize = numbered.lines().filter(|line| line.contains(" | ")).map(|line| line.len() + 1).sum();
+        assert!(kept <= budget * CHARS_PER_TOKEN);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_left_as_is_in_the_editor_is_sent_unchanged() {
+        std::env::set_var("EDITOR", "true");
+        let messages = vec![Message::new(Role::User, "hi")];
+        let edited = edit_messages(&messages).await.unwrap();
+        assert_eq!(serde_json::to_value(&edited).unwrap(), serde_json::to_value(&messages).unwrap());
+    }
+
+    #[tokio::test]
+    async fn status_line_stays_out_of_the_conversation_and_across_writes() {
+        let path = std::env::temp_dir().join(format!("chat-md-status-{}.md", std::process::id()));
+        let mut config = test_config();
+        config.status_line = true;
+        std::fs::write(&path, "---\ntitle: t\n---\nHi\n\n").unwrap();
+        set_status(&config, &path, "sending", Some("---\ntitle: t\n---\nHi\n\n")).await;
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(raw, "---\ntitle: t\n---\n<!-- status: sending -->\nHi\n\n");
+        let content = read_chat_file(&path, false).await.unwrap();
+        assert_eq!(content, "---\ntitle: t\n---\nHi\n\n");
+        assert_eq!(split_turns(&content)[0].text, "Hi\n\n");
+
+        write_chat_file(&path, "---\ntitle: t\n---\nHi\n***\nHello!\n***\n", false).await.unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("---\n<!-- status: sending -->\nHi\n***"));
+
+        // Edits the monitor hasn't seen yet are not raced
+        set_status(&config, &path, "error: x --> y", Some("something else")).await;
+        assert!(std::fs::read_to_string(&path).unwrap().contains("status: sending"));
+        set_status(&config, &path, "error: x --> y", None).await;
+        assert!(std::fs::read_to_string(&path).unwrap().contains("<!-- status: error: x -> y -->\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn subprocesses_do_not_inherit_the_api_keys() {
+        let _ = test_config();
+        let output = subprocess("env").output().await.unwrap();
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("DEEPSEEK_API_KEY"));
+    }
+}